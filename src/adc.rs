@@ -0,0 +1,241 @@
+//!
+//! Application Default Credentials (ADC) 互換の資格情報の検出と書き出し
+//!
+//! ADC の検索順のうち、環境変数 `GOOGLE_APPLICATION_CREDENTIALS` と gcloud のファイルまでを扱い、
+//! 対応する資格情報の種類は `authorized_user` だけです。
+//! `service_account` などの他の種類と、メタデータサーバー (Compute Engine や Cloud Run など) からの取得は対象外で、
+//! その場合はエラーとします。
+//!
+//! # References
+//! - [アプリケーションのデフォルト認証情報の仕組み](https://cloud.google.com/docs/authentication/application-default-credentials?hl=ja)
+
+use crate::{configuration, gauth2, transport};
+use log::info;
+
+/// 資格情報ファイルのパスを指定する環境変数
+const CREDENTIALS_ENV: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+
+/// ADC のファイル名
+const WELL_KNOWN_FILE_NAME: &str = "application_default_credentials.json";
+
+///
+/// gcloud の設定ディレクトリを返します。
+///
+/// `$CLOUDSDK_CONFIG` または `~/.config/gcloud` (Windows では `%APPDATA%\gcloud`)
+///
+fn get_gcloud_config_directory() -> Option<std::path::PathBuf> {
+	if let Some(dir) = std::env::var_os("CLOUDSDK_CONFIG").filter(|s| !s.is_empty()) {
+		return Some(std::path::PathBuf::from(dir));
+	}
+	if cfg!(windows) {
+		return std::env::var_os("APPDATA").map(|dir| std::path::Path::new(&dir).join("gcloud"));
+	}
+	return std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config").join("gcloud"));
+}
+
+///
+/// gcloud が使用する ADC ファイルのパスを返します。
+///
+pub fn get_well_known_file() -> Option<std::path::PathBuf> {
+	return get_gcloud_config_directory().map(|dir| dir.join(WELL_KNOWN_FILE_NAME));
+}
+
+///
+/// ADC の検索順に従って、資格情報ファイルを探します。
+///
+/// 1. 環境変数 `GOOGLE_APPLICATION_CREDENTIALS` で指定されたファイル
+/// 2. gcloud の `application_default_credentials.json`
+///
+pub fn find_credentials_file() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+	if let Some(path) = std::env::var_os(CREDENTIALS_ENV).filter(|s| !s.is_empty()) {
+		let path = std::path::PathBuf::from(path);
+		if !path.is_file() {
+			return Err(format!(
+				"{} で指定されたファイルがみつかりません。[{}]",
+				CREDENTIALS_ENV,
+				path.display()
+			)
+			.into());
+		}
+		return Ok(path);
+	}
+
+	if let Some(path) = get_well_known_file() {
+		if path.is_file() {
+			return Ok(path);
+		}
+	}
+
+	return Err(format!(
+		"Application Default Credentials がみつかりません。{} を設定するか、export-adc で書き出してください。(メタデータサーバーからの取得には対応していません)",
+		CREDENTIALS_ENV
+	)
+	.into());
+}
+
+///
+/// ADC の検索順に従って資格情報を読み込み、アクセストークンを提供するものを返します。
+///
+/// `authorized_user` 以外の種類の資格情報はエラーとします。
+///
+/// # Arguments
+/// * `options` - HTTP クライアントの設定
+///
+pub fn resolve(
+	options: &transport::HttpClientOptions,
+) -> Result<Box<dyn gauth2::TokenProvider>, Box<dyn std::error::Error>> {
+	let path = find_credentials_file()?;
+	info!("ADC: {}", path.display());
+
+	let text = std::fs::read_to_string(&path)?;
+	let value: serde_json::Value = serde_json::from_str(&text)?;
+	let credential_type = value.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+	if credential_type != "authorized_user" {
+		return Err(format!(
+			"この種類の資格情報には対応していません。authorized_user だけに対応しています。[{}: {}]",
+			credential_type,
+			path.display()
+		)
+		.into());
+	}
+
+	let credentials = configuration::parse_client_secret(&path)?;
+	let user = match credentials {
+		configuration::Credentials::AuthorizedUser(user) => user,
+		_ => return Err("authorized_user ではありません。".into()),
+	};
+
+	let mut service = gauth2::GoogleOAuth2::with_http_options(&user.client_id, &user.client_secret, options)?;
+	service.set_refresh_token(&user.refresh_token);
+
+	return Ok(Box::new(service));
+}
+
+///
+/// ADC の出力先を決め、上書きしてよいかを確認します。
+///
+/// 認可手続きを始める前に呼び出し、既存のファイルを上書きしない場合は先にエラーとします。
+///
+/// # Arguments
+/// * `path` - 出力先。省略した場合は gcloud の `application_default_credentials.json`
+/// * `force` - 既存のファイルを上書きするかどうか
+///
+pub fn export_destination(
+	path: Option<&std::path::Path>,
+	force: bool,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+	let path = match path {
+		Some(path) => path.to_path_buf(),
+		None => get_well_known_file().ok_or("ADC の出力先を決定できません。")?,
+	};
+	if path.exists() && !force {
+		return Err(format!(
+			"ファイルが既にあります。上書きする場合は --force を指定してください。[{}]",
+			path.display()
+		)
+		.into());
+	}
+	return Ok(path);
+}
+
+///
+/// 上書きする前のファイルを残す、バックアップのパス (`PATH.bak`) を返します。
+///
+fn backup_path(path: &std::path::Path) -> std::path::PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_os_string();
+	name.push(".bak");
+	return path.with_file_name(name);
+}
+
+///
+/// 認可済みの資格情報を ADC 形式 (authorized_user) で書き出します。
+///
+/// 既存のファイルは、`force` が指定された場合だけ `PATH.bak` にコピーしてから上書きします。
+///
+/// # Arguments
+/// * `service` - 認可手続きを終えたインスタンス
+/// * `path` - 出力先。省略した場合は gcloud の `application_default_credentials.json`
+/// * `force` - 既存のファイルを上書きするかどうか
+///
+pub fn export(
+	service: &gauth2::GoogleOAuth2,
+	path: Option<&std::path::Path>,
+	force: bool,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+	let path = export_destination(path, force)?;
+
+	let user = service.to_authorized_user()?;
+	let json = serde_json::to_string_pretty(&user)?;
+	if path.is_file() {
+		let backup = backup_path(&path);
+		std::fs::copy(&path, &backup)
+			.map_err(|e| format!("バックアップを作成できません。[{}] 理由: {}", backup.display(), e))?;
+		info!("既存の ADC をバックアップしました。[{}]", backup.display());
+	}
+	crate::util::write_secret_file(&path, json.as_bytes())?;
+
+	return Ok(path);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn temp_dir(name: &str) -> std::path::PathBuf {
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		return dir;
+	}
+
+	#[test]
+	fn export_refuses_to_overwrite_without_force_and_backs_up_with_force() {
+		let dir = temp_dir("adc-export");
+		let path = dir.join(WELL_KNOWN_FILE_NAME);
+		let (mut service, _transport) = crate::gauth2::tests::mock_service();
+		service.set_refresh_token("1//new");
+
+		// 新しいファイルは --force 無しで書き出せます。
+		assert_eq!(export(&service, Some(&path), false).unwrap(), path);
+		assert!(!backup_path(&path).exists());
+
+		std::fs::write(&path, r#"{"type":"authorized_user","refresh_token":"1//old"}"#).unwrap();
+		let error = export(&service, Some(&path), false).unwrap_err().to_string();
+		assert!(error.contains("--force"), "{}", error);
+		assert!(export_destination(Some(&path), false).is_err());
+		assert!(std::fs::read_to_string(&path).unwrap().contains("1//old"));
+
+		export(&service, Some(&path), true).unwrap();
+		assert!(std::fs::read_to_string(&path).unwrap().contains("1//new"));
+		assert!(std::fs::read_to_string(backup_path(&path))
+			.unwrap()
+			.contains("1//old"));
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn resolve_rejects_unsupported_credential_types() {
+		let dir = temp_dir("adc-resolve");
+		let path = dir.join("service-account.json");
+		std::fs::write(
+			&path,
+			r#"{"type":"service_account","client_email":"sa@example.iam.gserviceaccount.com"}"#,
+		)
+		.unwrap();
+
+		// 環境変数に触れるテストはこれだけにしておきます。
+		std::env::set_var(CREDENTIALS_ENV, &path);
+		let service_account = resolve(&transport::HttpClientOptions::default()).map(|_| ());
+		std::env::set_var(CREDENTIALS_ENV, dir.join("missing.json"));
+		let missing = find_credentials_file();
+		std::env::remove_var(CREDENTIALS_ENV);
+
+		let error = service_account.unwrap_err().to_string();
+		assert!(error.contains("service_account"), "{}", error);
+		assert!(error.contains("authorized_user"), "{}", error);
+		assert!(missing.unwrap_err().to_string().contains(CREDENTIALS_ENV));
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
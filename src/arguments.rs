@@ -0,0 +1,350 @@
+//!
+//! コマンドライン引数の解析
+//!
+
+use crate::{authorization, gauth2, store, transport, verification};
+
+///
+/// inspect コマンドの検証方法
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InspectVerification {
+	/// tokeninfo エンドポイントで確認します。
+	Remote,
+	/// Google の公開鍵 (JWKS) で署名を確認します。
+	Jwks,
+}
+
+impl std::str::FromStr for InspectVerification {
+	type Err = Box<dyn std::error::Error>;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		return match s {
+			"remote" => Ok(InspectVerification::Remote),
+			"jwks" => Ok(InspectVerification::Jwks),
+			_ => Err(format!("不明な検証方法です。[{}]", s).into()),
+		};
+	}
+}
+
+///
+/// コマンドライン引数
+///
+#[derive(Debug, Default, Clone)]
+pub struct Arguments {
+	/// client_secret*.json のパス (--client-secret)
+	pub client_secret: Option<String>,
+	/// ログの詳細度 (-v の数)
+	pub verbosity: u8,
+	/// HTTP クライアントの設定 (--timeout, --connect-timeout, --proxy, --ca-cert, --user-agent)
+	pub http_options: transport::HttpClientOptions,
+	/// 認可リクエストの追加のパラメーター (--access-type, --prompt, --scope, --login-hint など)
+	pub authorization_options: authorization::AuthorizationOptions,
+	/// 資格情報の保存先 (--store。省略した場合は --token-cache と --encrypted から決めます)
+	pub store: Option<store::StoreKind>,
+	/// 資格情報を保存するファイルのパス (--token-cache)
+	pub token_cache: Option<std::path::PathBuf>,
+	/// 資格情報を暗号化して保存するかどうか (--encrypted)
+	pub encrypted: bool,
+	/// 暗号化の鍵ファイルのパス (--key-file)
+	pub key_file: Option<std::path::PathBuf>,
+	/// 認可手続きの方法 (--login)
+	pub login_method: gauth2::LoginMethod,
+	/// 更新トークンが無効になった場合に、認可手続きをやり直すかどうか (--relogin)
+	pub relogin: bool,
+	/// 既存のファイルを上書きするかどうか (--force)
+	pub force: bool,
+	/// 公開ステータスが「テスト」のアプリケーションかどうか (--testing-mode)
+	pub testing_mode: bool,
+	/// 許可する Google Workspace のドメイン (--allowed-domain)
+	pub allowed_domains: Vec<String>,
+	/// アクセストークンの検証方針 (--require-scope, --min-lifetime, --require-verified-email)
+	pub verification_policy: verification::VerificationPolicy,
+	/// tokeninfo エンドポイント (--tokeninfo-endpoint)
+	pub tokeninfo_endpoint: Option<String>,
+	/// inspect コマンドの検証方法 (--verify)
+	pub inspect_verification: Option<InspectVerification>,
+	/// 監査ログのパス (--audit-log)
+	pub audit_log: Option<std::path::PathBuf>,
+	/// 監査ログの大きさの上限 (--audit-log-max-size)
+	pub audit_log_max_size: Option<u64>,
+	/// サブコマンド
+	pub command: Option<String>,
+	/// サブコマンドの引数
+	pub parameters: Vec<String>,
+}
+
+///
+/// オプションの値を取り出します。"--name value" と "--name=value" の両方を受け付けます。
+///
+/// # Arguments
+/// * `name` - オプション名
+/// * `current` - 現在の引数
+/// * `iter` - 後続の引数
+///
+fn take_option_value(
+	name: &str,
+	current: &str,
+	iter: &mut dyn Iterator<Item = String>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+	if current == name {
+		let value = iter.next();
+		if value.is_none() {
+			return Err(format!("{} には値が必要です。", name).into());
+		}
+		return Ok(value);
+	}
+	let prefix = format!("{}=", name);
+	if let Some(value) = current.strip_prefix(&prefix) {
+		return Ok(Some(value.to_string()));
+	}
+	return Ok(None);
+}
+
+///
+/// 秒数を解析します。
+///
+fn parse_seconds(name: &str, value: &str) -> Result<std::time::Duration, Box<dyn std::error::Error>> {
+	let seconds: u64 = value
+		.parse()
+		.map_err(|_| format!("{} には秒数を指定してください。[{}]", name, value))?;
+	return Ok(std::time::Duration::from_secs(seconds));
+}
+
+///
+/// 大きさ (バイト) を解析します。K, M, G (1024 の累乗) を付けることができます。
+///
+fn parse_size(name: &str, value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+	let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+		Some((index, _)) => value.split_at(index),
+		None => (value, ""),
+	};
+	let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+		"" => 1,
+		"K" => 1024,
+		"M" => 1024 * 1024,
+		"G" => 1024 * 1024 * 1024,
+		_ => 0,
+	};
+	let size = number
+		.parse::<u64>()
+		.ok()
+		.and_then(|n| n.checked_mul(multiplier))
+		.filter(|n| 0 < *n);
+	return size.ok_or_else(|| format!("{} には大きさを指定してください。[{}]", name, value).into());
+}
+
+///
+/// コマンドライン引数を解析します。
+///
+pub fn parse() -> Result<Arguments, Box<dyn std::error::Error>> {
+	return parse_from(std::env::args().skip(1));
+}
+
+///
+/// 与えられた引数を解析します。
+///
+/// # Arguments
+/// * `args` - プログラム名を除いた引数
+///
+pub fn parse_from(args: impl Iterator<Item = String>) -> Result<Arguments, Box<dyn std::error::Error>> {
+	let mut arguments = Arguments::default();
+	let mut iter = args;
+	while let Some(arg) = iter.next() {
+		if let Some(value) = take_option_value("--client-secret", &arg, &mut iter)? {
+			arguments.client_secret = Some(value);
+			continue;
+		}
+		if let Some(value) = take_option_value("--timeout", &arg, &mut iter)? {
+			arguments.http_options.timeout = parse_seconds("--timeout", &value)?;
+			continue;
+		}
+		if let Some(value) = take_option_value("--connect-timeout", &arg, &mut iter)? {
+			arguments.http_options.connect_timeout = parse_seconds("--connect-timeout", &value)?;
+			continue;
+		}
+		if let Some(value) = take_option_value("--proxy", &arg, &mut iter)? {
+			arguments.http_options.proxy = Some(value);
+			continue;
+		}
+		if let Some(value) = take_option_value("--ca-cert", &arg, &mut iter)? {
+			arguments.http_options.extra_root_certificates.push(value.into());
+			continue;
+		}
+		if let Some(value) = take_option_value("--user-agent", &arg, &mut iter)? {
+			arguments.http_options.user_agent = value;
+			continue;
+		}
+		if let Some(value) = take_option_value("--access-type", &arg, &mut iter)? {
+			arguments.authorization_options.access_type = Some(value.parse()?);
+			continue;
+		}
+		if let Some(value) = take_option_value("--prompt", &arg, &mut iter)? {
+			for prompt in value.split([' ', ',']).filter(|p| !p.is_empty()) {
+				arguments.authorization_options.prompt.push(prompt.parse()?);
+			}
+			continue;
+		}
+		if let Some(value) = take_option_value("--scope", &arg, &mut iter)? {
+			for scope in value.split([' ', ',']).filter(|s| !s.is_empty()) {
+				arguments.authorization_options.scopes.push(scope.to_string());
+			}
+			continue;
+		}
+		if let Some(value) = take_option_value("--login-hint", &arg, &mut iter)? {
+			arguments.authorization_options.login_hint = Some(value);
+			continue;
+		}
+		if let Some(value) = take_option_value("--hd", &arg, &mut iter)? {
+			arguments.authorization_options.hd = Some(value);
+			continue;
+		}
+		if let Some(value) = take_option_value("--max-age", &arg, &mut iter)? {
+			let max_age = parse_seconds("--max-age", &value)?;
+			arguments.authorization_options.max_age = Some(max_age.as_secs());
+			continue;
+		}
+		if arg == "--include-granted-scopes" {
+			arguments.authorization_options.include_granted_scopes = Some(true);
+			continue;
+		}
+		if arg == "--offline" {
+			let options = &mut arguments.authorization_options;
+			options.access_type = Some(authorization::AccessType::Offline);
+			if !options.prompt.contains(&authorization::Prompt::Consent) {
+				options.prompt.push(authorization::Prompt::Consent);
+			}
+			continue;
+		}
+		if let Some(value) = take_option_value("--store", &arg, &mut iter)? {
+			arguments.store = Some(value.parse()?);
+			continue;
+		}
+		if let Some(value) = take_option_value("--token-cache", &arg, &mut iter)? {
+			arguments.token_cache = Some(value.into());
+			continue;
+		}
+		if let Some(value) = take_option_value("--key-file", &arg, &mut iter)? {
+			arguments.key_file = Some(value.into());
+			arguments.encrypted = true;
+			continue;
+		}
+		if arg == "--encrypted" {
+			arguments.encrypted = true;
+			continue;
+		}
+		if let Some(value) = take_option_value("--login", &arg, &mut iter)? {
+			arguments.login_method = value.parse()?;
+			continue;
+		}
+		if let Some(value) = take_option_value("--allowed-domain", &arg, &mut iter)? {
+			for domain in value.split([' ', ',']).filter(|d| !d.is_empty()) {
+				arguments.allowed_domains.push(domain.to_string());
+			}
+			continue;
+		}
+		if let Some(value) = take_option_value("--require-scope", &arg, &mut iter)? {
+			for scope in value.split([' ', ',']).filter(|s| !s.is_empty()) {
+				arguments
+					.verification_policy
+					.required_scopes
+					.push(scope.to_string());
+			}
+			continue;
+		}
+		if let Some(value) = take_option_value("--min-lifetime", &arg, &mut iter)? {
+			let lifetime = parse_seconds("--min-lifetime", &value)?;
+			arguments.verification_policy.min_remaining_lifetime =
+				Some(chrono::Duration::seconds(lifetime.as_secs() as i64));
+			continue;
+		}
+		if let Some(value) = take_option_value("--tokeninfo-endpoint", &arg, &mut iter)? {
+			arguments.tokeninfo_endpoint = Some(value);
+			continue;
+		}
+		if let Some(value) = take_option_value("--audit-log", &arg, &mut iter)? {
+			arguments.audit_log = Some(value.into());
+			continue;
+		}
+		if let Some(value) = take_option_value("--audit-log-max-size", &arg, &mut iter)? {
+			arguments.audit_log_max_size = Some(parse_size("--audit-log-max-size", &value)?);
+			continue;
+		}
+		if let Some(value) = take_option_value("--verify", &arg, &mut iter)? {
+			arguments.inspect_verification = Some(value.parse()?);
+			continue;
+		}
+		if arg == "--require-verified-email" {
+			arguments.verification_policy.require_verified_email = true;
+			continue;
+		}
+		if arg == "--relogin" {
+			arguments.relogin = true;
+			continue;
+		}
+		if arg == "--force" {
+			arguments.force = true;
+			continue;
+		}
+		if arg == "--testing-mode" {
+			arguments.testing_mode = true;
+			continue;
+		}
+		if arg == "--verbose" {
+			arguments.verbosity = arguments.verbosity.saturating_add(1);
+			continue;
+		}
+		if arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v') {
+			arguments.verbosity = arguments.verbosity.saturating_add((arg.len() - 1).min(255) as u8);
+			continue;
+		}
+		if arg.starts_with('-') {
+			return Err(format!("不明な引数です。[{}]", arg).into());
+		}
+		if arguments.command.is_none() {
+			arguments.command = Some(arg);
+		} else {
+			arguments.parameters.push(arg);
+		}
+	}
+	arguments.authorization_options.validate()?;
+	arguments
+		.authorization_options
+		.validate_allowed_domains(&arguments.allowed_domains)?;
+	resolve_store(&mut arguments)?;
+	return Ok(arguments);
+}
+
+///
+/// 資格情報の保存先を決め、--token-cache, --encrypted, --key-file との組み合わせを確認します。
+///
+fn resolve_store(arguments: &mut Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	let kind = match arguments.store {
+		Some(kind) => kind,
+		// encrypt コマンドは、--token-cache なしで --key-file を使用します。
+		None if arguments.token_cache.is_none() => return Ok(()),
+		None if arguments.encrypted => store::StoreKind::EncryptedFile,
+		None => store::StoreKind::File,
+	};
+	match kind {
+		store::StoreKind::File | store::StoreKind::EncryptedFile => {
+			if arguments.token_cache.is_none() {
+				return Err("--store file と --store encrypted-file には --token-cache が必要です。".into());
+			}
+			if kind == store::StoreKind::File && arguments.encrypted {
+				return Err("--store file と --encrypted, --key-file は同時に指定できません。".into());
+			}
+			arguments.encrypted = kind == store::StoreKind::EncryptedFile;
+		}
+		store::StoreKind::Keyring | store::StoreKind::Memory => {
+			if arguments.token_cache.is_some() || arguments.encrypted {
+				return Err(
+					"--store keyring と --store memory には --token-cache, --encrypted, --key-file を指定できません。"
+						.into(),
+				);
+			}
+		}
+	}
+	arguments.store = Some(kind);
+	return Ok(());
+}
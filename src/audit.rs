@@ -0,0 +1,410 @@
+//!
+//! 認証のイベントの監査ログ
+//!
+//! 誰が、どのクライアントとスコープで認可し、いつトークンを更新、無効化したかを JSON Lines で追記します。
+//! トークンそのものは書き込まず、指紋 (SHA256 の先頭) だけを書き込みます。
+//! ファイルが上限の大きさを超える場合は `PATH.1`, `PATH.2`, ... に移してから書き込みます。
+
+use crate::events::{TokenEvent, TokenEventObserver};
+use crate::gauth2::{TokenData, UserProfile};
+use crate::lock::CredentialLock;
+use crate::util;
+use log::warn;
+
+/// ファイルの大きさの既定の上限 (バイト)
+pub const DEFAULT_AUDIT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// 残す古いファイルの数
+const AUDIT_LOG_BACKUPS: u32 = 5;
+
+/// ログイン (認可済みのユーザーの確認) を示すイベント名
+const LOGIN_EVENT: &str = "login";
+
+///
+/// 監査ログの 1 行
+///
+#[derive(serde_derive::Serialize, Debug, Clone)]
+pub struct AuditRecord {
+	/// 日時
+	pub timestamp: chrono::DateTime<chrono::Utc>,
+	/// イベント (login, authorized, refreshed, revoked, invalid_grant)
+	pub event: String,
+	/// 結果 (success, failure)
+	pub result: String,
+	/// クライアント ID
+	pub client_id: String,
+	/// ユーザー ID
+	pub sub: Option<String>,
+	/// メールアドレス
+	pub email: Option<String>,
+	/// sub と email を、署名を確認した ID トークンか userinfo で確認したかどうか
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub account_verified: Option<bool>,
+	/// スコープ
+	pub scopes: Vec<String>,
+	/// アクセストークンの指紋
+	pub access_token_id: Option<String>,
+	/// 更新トークンの指紋
+	pub refresh_token_id: Option<String>,
+	/// アクセストークンの有効期限
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+	/// 失敗の理由
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	/// プロセス ID
+	pub pid: u32,
+}
+
+///
+/// 監査ログ
+///
+/// `TokenEventObserver` として `GoogleOAuth2` に登録すると、認可、更新、無効化を記録します。
+///
+pub struct AuditLog {
+	path: std::path::PathBuf,
+	max_size: u64,
+	/// 最後に確認したユーザー (ID トークンの無いイベントを補います)
+	account: std::sync::Mutex<Option<(String, Option<String>)>>,
+}
+
+impl AuditLog {
+	///
+	/// 新しいインスタンスを返します。
+	///
+	/// # Arguments
+	/// * `path` - ファイルパス
+	/// * `max_size` - ファイルの大きさの上限 (バイト)
+	///
+	pub fn new(path: &std::path::Path, max_size: u64) -> Self {
+		return Self {
+			path: path.to_path_buf(),
+			max_size,
+			account: std::sync::Mutex::new(None),
+		};
+	}
+
+	///
+	/// ユーザー情報で確認したログインを記録します。
+	///
+	/// 以降のイベントに ID トークンが無い場合は、このユーザーで補います。
+	///
+	/// # Arguments
+	/// * `client_id` - クライアント ID
+	/// * `user_profile` - ユーザー情報
+	/// * `token_data` - 使用した資格情報
+	///
+	pub fn record_login(&self, client_id: &str, user_profile: &UserProfile, token_data: &TokenData) {
+		let sub = user_profile.sub().to_string();
+		let email = user_profile.email().map(|e| e.to_string());
+		*self.account.lock().unwrap_or_else(|e| e.into_inner()) = Some((sub.clone(), email.clone()));
+
+		let record = AuditRecord {
+			sub: Some(sub),
+			account_verified: Some(true),
+			email,
+			scopes: token_data.scopes().into_iter().map(|s| s.to_string()).collect(),
+			access_token_id: Some(token_data.access_token.as_str())
+				.filter(|t| !t.is_empty())
+				.map(util::token_fingerprint),
+			refresh_token_id: token_data.refresh_token().map(util::token_fingerprint),
+			expires_at: token_data.expires_at(),
+			..Self::new_record(LOGIN_EVENT, "success", client_id)
+		};
+		self.write(&record);
+	}
+
+	///
+	/// 失敗したログインを記録します。
+	///
+	/// # Arguments
+	/// * `client_id` - クライアント ID
+	/// * `error` - 失敗の理由
+	///
+	pub fn record_login_failure(&self, client_id: &str, error: &dyn std::error::Error) {
+		let record = AuditRecord {
+			error: Some(error.to_string()),
+			..Self::new_record(LOGIN_EVENT, "failure", client_id)
+		};
+		self.write(&record);
+	}
+
+	///
+	/// 空の記録を返します。
+	///
+	fn new_record(event: &str, result: &str, client_id: &str) -> AuditRecord {
+		return AuditRecord {
+			timestamp: chrono::Utc::now(),
+			event: event.to_string(),
+			result: result.to_string(),
+			client_id: client_id.to_string(),
+			sub: None,
+			email: None,
+			account_verified: None,
+			scopes: vec![],
+			access_token_id: None,
+			refresh_token_id: None,
+			expires_at: None,
+			error: None,
+			pid: std::process::id(),
+		};
+	}
+
+	///
+	/// 1 行を追記します。書き込めなくても、ログインや更新そのものは続けられるように警告だけを出力します。
+	///
+	fn write(&self, record: &AuditRecord) {
+		if let Err(e) = self.append(record) {
+			warn!("監査ログに書き込めません。[{}] 理由: {}", self.path.display(), e);
+		}
+	}
+
+	///
+	/// 1 行を追記します。上限の大きさを超える場合は、先に古いファイルに移します。
+	///
+	pub fn append(&self, record: &AuditRecord) -> Result<(), Box<dyn std::error::Error>> {
+		use std::io::Write;
+
+		let mut line = serde_json::to_string(record)?;
+		line.push('\n');
+
+		// 他のプロセスと、移動と追記が入れ替わらないようにします。
+		let _lock = CredentialLock::acquire(&self.path)?;
+		let size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+		if 0 < size && self.max_size < size + line.len() as u64 {
+			self.rotate()?;
+		}
+
+		let mut options = std::fs::OpenOptions::new();
+		options.append(true).create(true);
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::OpenOptionsExt;
+			options.mode(0o600);
+		}
+		let mut file = options
+			.open(&self.path)
+			.map_err(|e| format!("監査ログを開けません。[{}] 理由: {}", self.path.display(), e))?;
+		file.write_all(line.as_bytes())?;
+		file.sync_data()?;
+		return Ok(());
+	}
+
+	///
+	/// 古いファイルを `PATH.1`, `PATH.2`, ... に移し、最も古いものを削除します。
+	///
+	fn rotate(&self) -> Result<(), Box<dyn std::error::Error>> {
+		let backup = |n: u32| {
+			let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+			name.push(format!(".{}", n));
+			return self.path.with_file_name(name);
+		};
+		let oldest = backup(AUDIT_LOG_BACKUPS);
+		if oldest.exists() {
+			std::fs::remove_file(&oldest)?;
+		}
+		for n in (1..AUDIT_LOG_BACKUPS).rev() {
+			let from = backup(n);
+			if from.exists() {
+				std::fs::rename(&from, backup(n + 1))?;
+			}
+		}
+		std::fs::rename(&self.path, backup(1))?;
+		return Ok(());
+	}
+}
+
+impl TokenEventObserver for AuditLog {
+	fn on_event(&self, event: &TokenEvent) {
+		let mut sub = event.account.sub.clone();
+		let mut email = event.account.email.clone();
+		let mut account_verified = event.account.verified;
+		// 署名を確認していない ID トークンより、userinfo で確認したユーザーを優先します。
+		if !account_verified {
+			if let Some((known_sub, known_email)) =
+				self.account.lock().unwrap_or_else(|e| e.into_inner()).clone()
+			{
+				sub = Some(known_sub);
+				email = known_email;
+				account_verified = true;
+			}
+		}
+		let result = if event.kind.is_failure() {
+			"failure"
+		} else {
+			"success"
+		};
+		let record = AuditRecord {
+			timestamp: event.occurred_at,
+			account_verified: sub.as_ref().map(|_| account_verified),
+			sub,
+			email,
+			scopes: event.scopes.clone(),
+			access_token_id: event.access_token_fingerprint.clone(),
+			refresh_token_id: event.refresh_token_fingerprint.clone(),
+			expires_at: event.expires_at,
+			..Self::new_record(event.kind.as_str(), result, &event.client_id)
+		};
+		self.write(&record);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::events::TokenEventKind;
+
+	fn temp_dir(name: &str) -> std::path::PathBuf {
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		return dir;
+	}
+
+	/// client_id で見分けられる、長さの揃った記録
+	fn record(n: u32) -> AuditRecord {
+		use chrono::TimeZone;
+
+		return AuditRecord {
+			timestamp: chrono::Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+			..AuditLog::new_record("refreshed", "success", &format!("client-{}", n))
+		};
+	}
+
+	fn line_length() -> u64 {
+		return serde_json::to_string(&record(0)).unwrap().len() as u64 + 1;
+	}
+
+	/// ファイルの各行の client_id を返します。
+	fn client_ids(path: &std::path::Path) -> Vec<String> {
+		let text = std::fs::read_to_string(path).unwrap();
+		return text
+			.lines()
+			.map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["client_id"].to_string())
+			.map(|id| id.trim_matches('"').to_string())
+			.collect();
+	}
+
+	fn backup(path: &std::path::Path, n: u32) -> std::path::PathBuf {
+		return path.with_file_name(format!("audit.jsonl.{}", n));
+	}
+
+	#[test]
+	fn append_rotates_when_the_next_line_exceeds_max_size() {
+		let dir = temp_dir("audit-size");
+		let path = dir.join("audit.jsonl");
+		let log = AuditLog::new(&path, line_length() * 2);
+
+		log.append(&record(1)).unwrap();
+		log.append(&record(2)).unwrap();
+		assert_eq!(client_ids(&path), vec!["client-1", "client-2"]);
+		assert!(!backup(&path, 1).exists());
+
+		log.append(&record(3)).unwrap();
+		assert_eq!(client_ids(&path), vec!["client-3"]);
+		assert_eq!(client_ids(&backup(&path, 1)), vec!["client-1", "client-2"]);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn rotate_shifts_backups_and_drops_the_oldest() {
+		let dir = temp_dir("audit-rotate");
+		let path = dir.join("audit.jsonl");
+		// 1 行ごとに移します。
+		let log = AuditLog::new(&path, 1);
+
+		let count = AUDIT_LOG_BACKUPS + 2;
+		for n in 1..=count {
+			log.append(&record(n)).unwrap();
+		}
+
+		assert_eq!(client_ids(&path), vec![format!("client-{}", count)]);
+		for n in 1..=AUDIT_LOG_BACKUPS {
+			assert_eq!(
+				client_ids(&backup(&path, n)),
+				vec![format!("client-{}", count - n)]
+			);
+		}
+		assert!(!backup(&path, AUDIT_LOG_BACKUPS + 1).exists());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn records_are_json_lines_with_fingerprints_instead_of_tokens() {
+		let dir = temp_dir("audit-content");
+		let path = dir.join("audit.jsonl");
+		let log = AuditLog::new(&path, DEFAULT_AUDIT_LOG_MAX_SIZE);
+
+		let unverified_id_token = crate::id_token::tests::sign(&crate::id_token::tests::valid_claims());
+		let token_data: TokenData = serde_json::from_value(serde_json::json!({
+			"access_token": "ya29.access-secret",
+			"refresh_token": "1//refresh-secret",
+			"id_token": unverified_id_token,
+			"scope": "openid email",
+		}))
+		.unwrap();
+
+		// 署名を確認していない ID トークンのユーザーは、確認していないものとして記録します。
+		log.on_event(&TokenEvent::new(
+			TokenEventKind::Authorized,
+			"client-id",
+			&token_data,
+			false,
+		));
+
+		let profile: UserProfile =
+			serde_json::from_str(r#"{"sub":"999","email":"known@example.com"}"#).unwrap();
+		log.record_login("client-id", &profile, &token_data);
+		// ログインの後は、確認したユーザーで補います。
+		log.on_event(&TokenEvent::new(
+			TokenEventKind::Refreshed,
+			"client-id",
+			&token_data,
+			false,
+		));
+		log.record_login_failure("client-id", &std::io::Error::other("network down"));
+
+		let text = std::fs::read_to_string(&path).unwrap();
+		assert!(!text.contains("ya29.access-secret"));
+		assert!(!text.contains("1//refresh-secret"));
+		assert!(!text.contains(&unverified_id_token));
+
+		let records: Vec<serde_json::Value> =
+			text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+		assert_eq!(records.len(), 4);
+
+		let authorized = &records[0];
+		assert_eq!(authorized["event"], "authorized");
+		assert_eq!(authorized["result"], "success");
+		assert_eq!(authorized["sub"], "1234567890");
+		assert_eq!(authorized["account_verified"], false);
+		assert_eq!(authorized["scopes"], serde_json::json!(["openid", "email"]));
+		assert_eq!(
+			authorized["access_token_id"],
+			util::token_fingerprint("ya29.access-secret")
+		);
+		assert_eq!(
+			authorized["refresh_token_id"],
+			util::token_fingerprint("1//refresh-secret")
+		);
+		assert_eq!(authorized["pid"], std::process::id());
+
+		assert_eq!(records[1]["event"], "login");
+		assert_eq!(records[1]["sub"], "999");
+		assert_eq!(records[1]["account_verified"], true);
+
+		assert_eq!(records[2]["event"], "refreshed");
+		assert_eq!(records[2]["sub"], "999");
+		assert_eq!(records[2]["email"], "known@example.com");
+		assert_eq!(records[2]["account_verified"], true);
+
+		assert_eq!(records[3]["event"], "login");
+		assert_eq!(records[3]["result"], "failure");
+		assert_eq!(records[3]["error"], "network down");
+		assert!(records[3].get("account_verified").is_none());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
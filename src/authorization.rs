@@ -0,0 +1,236 @@
+//!
+//! 認可リクエストのパラメーター
+//!
+//! # References
+//! - [OAuth 2.0 for Mobile & Desktop Apps - Step 2](https://developers.google.com/identity/protocols/oauth2/native-app?hl=ja#step-2:-send-a-request-to-googles-oauth-2.0-server)
+//! - [OpenID Connect - Authentication URI parameters](https://developers.google.com/identity/openid-connect/openid-connect?hl=ja#authenticationuriparameters)
+
+use crate::error::OAuth2Error;
+
+/// 既定のスコープ
+pub const DEFAULT_SCOPES: [&str; 3] = ["openid", "profile", "email"];
+
+///
+/// access_type
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+	/// 更新トークンを発行しません。
+	Online,
+	/// 更新トークンを発行します。
+	Offline,
+}
+
+impl AccessType {
+	///
+	/// パラメーターの値を返します。
+	///
+	pub fn as_str(&self) -> &'static str {
+		return match self {
+			AccessType::Online => "online",
+			AccessType::Offline => "offline",
+		};
+	}
+}
+
+impl std::str::FromStr for AccessType {
+	type Err = Box<dyn std::error::Error>;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		return match s {
+			"online" => Ok(AccessType::Online),
+			"offline" => Ok(AccessType::Offline),
+			_ => Err(format!("不明な access_type です。[{}]", s).into()),
+		};
+	}
+}
+
+///
+/// prompt
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+	/// 認証画面も同意画面も表示しません。
+	None,
+	/// 同意画面を表示します。
+	Consent,
+	/// アカウントの選択画面を表示します。
+	SelectAccount,
+}
+
+impl Prompt {
+	///
+	/// パラメーターの値を返します。
+	///
+	pub fn as_str(&self) -> &'static str {
+		return match self {
+			Prompt::None => "none",
+			Prompt::Consent => "consent",
+			Prompt::SelectAccount => "select_account",
+		};
+	}
+}
+
+impl std::str::FromStr for Prompt {
+	type Err = Box<dyn std::error::Error>;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		return match s {
+			"none" => Ok(Prompt::None),
+			"consent" => Ok(Prompt::Consent),
+			"select_account" => Ok(Prompt::SelectAccount),
+			_ => Err(format!("不明な prompt です。[{}]", s).into()),
+		};
+	}
+}
+
+///
+/// 認可リクエストの追加のパラメーター
+///
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizationOptions {
+	/// access_type
+	pub access_type: Option<AccessType>,
+	/// prompt (空白区切りで複数指定できます)
+	pub prompt: Vec<Prompt>,
+	/// スコープ (空であれば DEFAULT_SCOPES を使用します)
+	pub scopes: Vec<String>,
+	/// login_hint (メールアドレスまたは sub)
+	pub login_hint: Option<String>,
+	/// hd (Google Workspace のドメイン。画面上のヒントであり、制限ではありません)
+	pub hd: Option<String>,
+	/// nonce (ID トークンに含まれます。最初の認可リクエストだけで使い、次からは新しく生成します)
+	pub nonce: Option<String>,
+	/// max_age (最後に認証してからの許容秒数)
+	pub max_age: Option<u64>,
+	/// include_granted_scopes (段階的な認可)
+	pub include_granted_scopes: Option<bool>,
+}
+
+impl AuthorizationOptions {
+	///
+	/// 更新トークンを確実に受け取るための設定 (access_type=offline, prompt=consent) を返します。
+	///
+	/// Google は、同意済みのアプリケーションに対しては更新トークンを再発行しないため、同意画面を表示させます。
+	///
+	pub fn offline() -> Self {
+		return Self {
+			access_type: Some(AccessType::Offline),
+			prompt: vec![Prompt::Consent],
+			..Self::default()
+		};
+	}
+
+	///
+	/// scope パラメーターの値 (空白区切り) を返します。
+	///
+	pub fn scope(&self) -> String {
+		if self.scopes.is_empty() {
+			return DEFAULT_SCOPES.join(" ");
+		}
+		return self.scopes.join(" ");
+	}
+
+	///
+	/// openid スコープを含む (ID トークンが発行される) かどうかを返します。
+	///
+	pub fn has_openid_scope(&self) -> bool {
+		return self.scopes.is_empty() || self.scopes.iter().any(|s| s == "openid");
+	}
+
+	///
+	/// 矛盾する組み合わせや、不正な値が無いことを確認します。
+	///
+	/// ブラウザーを開く前に呼び出してください。
+	///
+	pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+		let invalid = |reason: String| -> Result<(), Box<dyn std::error::Error>> {
+			return Err(Box::new(OAuth2Error::InvalidAuthorizationOptions(reason)));
+		};
+
+		if self.prompt.contains(&Prompt::None) && 1 < self.prompt.len() {
+			return invalid("prompt=none は、他の prompt と同時に指定できません。".to_string());
+		}
+		for (i, prompt) in self.prompt.iter().enumerate() {
+			if self.prompt[..i].contains(prompt) {
+				return invalid(format!("prompt={} が重複しています。", prompt.as_str()));
+			}
+		}
+		for scope in &self.scopes {
+			if scope.is_empty() || scope.contains(char::is_whitespace) {
+				return invalid(format!("スコープが正しくありません。[{}]", scope));
+			}
+		}
+		let fields = [
+			("login_hint", &self.login_hint),
+			("hd", &self.hd),
+			("nonce", &self.nonce),
+		];
+		for (name, value) in fields {
+			if value.as_deref().is_some_and(|v| v.trim().is_empty()) {
+				return invalid(format!("{} が空です。", name));
+			}
+		}
+		if !self.has_openid_scope() && (self.nonce.is_some() || self.max_age.is_some()) {
+			return invalid("nonce と max_age には openid スコープが必要です。".to_string());
+		}
+		return Ok(());
+	}
+
+	///
+	/// hd のヒントが、許可するドメインに含まれることを確認します。
+	///
+	/// 含まれないドメインをヒントにすると、ログインの後で必ず拒否されるため、ブラウザーを開く前に呼び出してください。
+	/// 許可するドメインが無い場合と、hd が "*" (Workspace のアカウントのみ) の場合は何もしません。
+	///
+	/// # Arguments
+	/// * `allowed_domains` - 許可するドメイン
+	///
+	pub fn validate_allowed_domains(
+		&self,
+		allowed_domains: &[String],
+	) -> Result<(), Box<dyn std::error::Error>> {
+		let hd = match self.hd.as_deref().map(str::trim) {
+			Some(hd) if hd != "*" && !allowed_domains.is_empty() => hd,
+			_ => return Ok(()),
+		};
+		if allowed_domains.iter().any(|d| d.trim().eq_ignore_ascii_case(hd)) {
+			return Ok(());
+		}
+		return Err(Box::new(OAuth2Error::InvalidAuthorizationOptions(format!(
+			"hd が許可するドメインに含まれていません。[hd: {}, 許可するドメイン: {}]",
+			hd,
+			allowed_domains.join(", ")
+		))));
+	}
+
+	///
+	/// 認可エンドポイントの URL に追加するパラメーターを返します。(scope を除きます)
+	///
+	pub fn to_query_parameters(&self) -> Vec<(&'static str, String)> {
+		let mut params = vec![];
+		if let Some(access_type) = self.access_type {
+			params.push(("access_type", access_type.as_str().to_string()));
+		}
+		if !self.prompt.is_empty() {
+			let prompt: Vec<&str> = self.prompt.iter().map(|p| p.as_str()).collect();
+			params.push(("prompt", prompt.join(" ")));
+		}
+		if let Some(login_hint) = &self.login_hint {
+			params.push(("login_hint", login_hint.clone()));
+		}
+		if let Some(hd) = &self.hd {
+			params.push(("hd", hd.clone()));
+		}
+		if let Some(nonce) = &self.nonce {
+			params.push(("nonce", nonce.clone()));
+		}
+		if let Some(max_age) = self.max_age {
+			params.push(("max_age", max_age.to_string()));
+		}
+		if let Some(include_granted_scopes) = self.include_granted_scopes {
+			params.push(("include_granted_scopes", include_granted_scopes.to_string()));
+		}
+		return params;
+	}
+}
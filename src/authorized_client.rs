@@ -0,0 +1,135 @@
+//!
+//! Google API を呼び出すための認可済み HTTP クライアント
+//!
+
+use crate::gauth2::GoogleOAuth2;
+use crate::transport::{HttpRequest, HttpResponse};
+
+///
+/// 認可済み HTTP クライアント
+///
+/// リクエストごとに現在のアクセストークンを付け、有効期限の前に更新します。
+/// 401 が返された場合は、アクセストークンを強制的に更新して一度だけ再試行します。
+///
+pub struct AuthorizedClient {
+	service: GoogleOAuth2,
+}
+
+impl AuthorizedClient {
+	///
+	/// 認可手続きを終えた `GoogleOAuth2` から、新しいインスタンスを返します。
+	///
+	pub fn new(service: GoogleOAuth2) -> Self {
+		return Self { service };
+	}
+
+	///
+	/// 内部の `GoogleOAuth2` を返します。
+	///
+	pub fn service(&mut self) -> &mut GoogleOAuth2 {
+		return &mut self.service;
+	}
+
+	///
+	/// 内部の `GoogleOAuth2` を取り出します。
+	///
+	pub fn into_inner(self) -> GoogleOAuth2 {
+		return self.service;
+	}
+
+	///
+	/// リクエストを送信します。
+	///
+	pub fn send(&mut self, request: &HttpRequest) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		return self.service.send_authorized(request);
+	}
+
+	///
+	/// GET リクエストを送信します。
+	///
+	pub fn get(&mut self, url: &str) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		return self.send(&HttpRequest::get(url));
+	}
+
+	///
+	/// JSON を本文とする POST リクエストを送信します。
+	///
+	pub fn post_json(
+		&mut self,
+		url: &str,
+		body: &serde_json::Value,
+	) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		return self.send(&HttpRequest::post_json(url, body));
+	}
+
+	///
+	/// GET リクエストを送信し、2xx のレスポンスを JSON として解析します。
+	///
+	pub fn get_json<T: serde::de::DeserializeOwned>(
+		&mut self,
+		url: &str,
+	) -> Result<T, Box<dyn std::error::Error>> {
+		let response = self.get(url)?;
+		if !response.is_success() {
+			return Err(format!("HTTP {} {}", response.status, response.body).into());
+		}
+		let value: T = serde_json::from_str(&response.body)?;
+		return Ok(value);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gauth2::tests::mock_service;
+
+	const API_URL: &str = "https://www.googleapis.com/drive/v3/about";
+
+	/// 更新トークンで最初のアクセストークンを取得したクライアントを返します。
+	fn authorized_client() -> (AuthorizedClient, std::sync::Arc<crate::transport::MockTransport>) {
+		let (mut service, transport) = mock_service();
+		service.set_refresh_token("rt");
+		transport.push_response(200, r#"{"access_token":"at1","expires_in":3599}"#);
+		service.refresh_access_token().unwrap();
+		return (AuthorizedClient::new(service), transport);
+	}
+
+	/// リクエストの Authorization ヘッダーを返します。
+	fn authorization(request: &HttpRequest) -> Option<&str> {
+		return request
+			.headers
+			.iter()
+			.find(|(name, _)| name.eq_ignore_ascii_case("Authorization"))
+			.map(|(_, value)| value.as_str());
+	}
+
+	#[test]
+	fn retries_once_with_a_refreshed_token_on_401() {
+		let (mut client, transport) = authorized_client();
+		transport.push_response(401, r#"{"error":"invalid_token"}"#);
+		transport.push_response(200, r#"{"access_token":"at2","expires_in":3599}"#);
+		transport.push_response(200, r#"{"user":{}}"#);
+
+		let response = client.get(API_URL).unwrap();
+
+		assert_eq!(response.status, 200);
+		let requests = transport.requests();
+		assert_eq!(requests.len(), 5);
+		assert_eq!(authorization(&requests[2]), Some("Bearer at1"));
+		assert_eq!(requests[3].url, "https://oauth2.googleapis.com/token");
+		assert_eq!(authorization(&requests[4]), Some("Bearer at2"));
+	}
+
+	#[test]
+	fn does_not_retry_a_second_401() {
+		let (mut client, transport) = authorized_client();
+		transport.push_response(401, r#"{"error":"invalid_token"}"#);
+		transport.push_response(200, r#"{"access_token":"at2","expires_in":3599}"#);
+		transport.push_response(401, r#"{"error":"invalid_token"}"#);
+
+		let response = client.get(API_URL).unwrap();
+
+		assert_eq!(response.status, 401);
+		assert_eq!(transport.requests().len(), 5);
+	}
+}
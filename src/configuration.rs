@@ -1,27 +1,165 @@
 //!
-//!
+//! client_secret*.json の検出と読み込み
 //!
 
-use crate::info;
+use log::info;
+
+/// client_secret*.json のパスを指定する環境変数
+const CLIENT_SECRET_FILE_ENV: &str = "GOOGLE_CLIENT_SECRET_FILE";
+
+/// client_secret*.json の内容を直接指定する環境変数
+const CLIENT_SECRET_JSON_ENV: &str = "GOOGLE_CLIENT_SECRET_JSON";
+
+/// client_secret*.json を検索するディレクトリの深さの上限
+const MAX_SEARCH_DEPTH: u32 = 2;
 
 ///
-///
+/// OAuth クライアントの情報 ("installed" または "web")
 ///
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
-pub struct Installed {
+pub struct ClientInfo {
 	pub client_id: String,
 	pub client_secret: String,
+	#[serde(default)]
 	pub redirect_uris: Vec<String>,
 	pub auth_uri: String,
 	pub token_uri: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub project_id: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub auth_provider_x509_cert_url: Option<String>,
+	/// "web" の場合のみ
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub javascript_origins: Vec<String>,
 }
 
 ///
-///
+/// client_secret*.json
 ///
 #[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
 pub struct ClientSecret {
-	pub installed: Installed,
+	/// デスクトップ アプリ
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub installed: Option<ClientInfo>,
+	/// ウェブ アプリケーション
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub web: Option<ClientInfo>,
+}
+
+impl ClientSecret {
+	///
+	/// クライアントの情報を返します。
+	///
+	pub fn client(&self) -> Option<&ClientInfo> {
+		return self.installed.as_ref().or(self.web.as_ref());
+	}
+
+	///
+	/// ブラウザーによる認可で使用するリダイレクト URI を返します。
+	///
+	/// デスクトップ アプリは任意のポートのループバックを使えるため None を返します。
+	/// ウェブ アプリケーションは登録済みの URI しか使えないため、登録されたループバックの URI を返し、
+	/// 無ければエラーを返します。
+	///
+	pub fn loopback_redirect_uri(&self) -> Result<Option<&str>, Box<dyn std::error::Error>> {
+		if self.installed.is_some() {
+			return Ok(None);
+		}
+		let web = match &self.web {
+			Some(web) => web,
+			None => return Ok(None),
+		};
+		let uri = web
+			.redirect_uris
+			.iter()
+			.find(|uri| crate::util::loopback_port(uri).is_some())
+			.ok_or(
+				"ウェブ アプリケーションの client secret でブラウザーによる認可を行うには、http://localhost:PORT 形式のリダイレクト URI を登録するか、デスクトップ アプリの client secret を使用してください。",
+			)?;
+		return Ok(Some(uri));
+	}
+}
+
+///
+/// gcloud の authorized_user 形式 (application_default_credentials.json など)
+///
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+pub struct AuthorizedUser {
+	pub client_id: String,
+	pub client_secret: String,
+	pub refresh_token: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub quota_project_id: Option<String>,
+	/// 常に "authorized_user"
+	#[serde(rename = "type")]
+	pub credential_type: String,
+}
+
+///
+/// 読み込んだ資格情報
+///
+#[derive(Debug)]
+pub enum Credentials {
+	/// client_secret*.json
+	ClientSecret(Box<ClientSecret>),
+	/// 更新トークンを持つ authorized_user
+	AuthorizedUser(AuthorizedUser),
+}
+
+impl Credentials {
+	///
+	/// client id を返します。
+	///
+	pub fn client_id(&self) -> &str {
+		return match self {
+			Credentials::ClientSecret(secret) => &secret.client().unwrap().client_id,
+			Credentials::AuthorizedUser(user) => &user.client_id,
+		};
+	}
+
+	///
+	/// client secret を返します。
+	///
+	pub fn client_secret(&self) -> &str {
+		return match self {
+			Credentials::ClientSecret(secret) => &secret.client().unwrap().client_secret,
+			Credentials::AuthorizedUser(user) => &user.client_secret,
+		};
+	}
+}
+
+///
+/// アプリケーションの設定ディレクトリを返します。
+///
+/// `$XDG_CONFIG_HOME/r-google-oauth2` または `~/.config/r-google-oauth2` (Windows では `%APPDATA%\r-google-oauth2`)
+///
+pub fn get_config_directory() -> Option<std::path::PathBuf> {
+	let base = if cfg!(windows) {
+		std::env::var_os("APPDATA").map(std::path::PathBuf::from)
+	} else if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|s| !s.is_empty()) {
+		Some(std::path::PathBuf::from(dir))
+	} else {
+		std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+	};
+	return base.map(|dir| dir.join("r-google-oauth2"));
+}
+
+///
+/// client_secret*.json を検索する場所を、優先順に返します。
+///
+fn get_search_locations() -> Vec<std::path::PathBuf> {
+	let mut locations = vec![std::path::PathBuf::from(".")];
+	if let Some(dir) = get_config_directory() {
+		locations.push(dir);
+	}
+	return locations;
+}
+
+///
+/// 検索対象から除外するディレクトリかどうかを判定します。
+///
+fn is_skipped_directory(name: &str) -> bool {
+	return name.starts_with('.') || name == "target" || name == "node_modules";
 }
 
 ///
@@ -29,26 +167,40 @@ pub struct ClientSecret {
 ///
 /// # Arguments
 /// * `location` - 検索を開始する場所
+/// * `depth` - 残りの検索の深さ
 ///
-fn enumerate_client_secret(location: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-	let mut result: Vec<String> = vec![];
-	let unknown = std::path::Path::new(location);
-	if unknown.is_file() {
-		let file_name = unknown.file_name().unwrap().to_str().unwrap();
+fn enumerate_client_secret(
+	location: &std::path::Path,
+	depth: u32,
+) -> Result<Vec<std::path::PathBuf>, Box<dyn std::error::Error>> {
+	let mut result: Vec<std::path::PathBuf> = vec![];
+	// シンボリックリンクはたどりません。
+	let metadata = match std::fs::symlink_metadata(location) {
+		Ok(metadata) => metadata,
+		Err(_) => return Ok(result),
+	};
+	if metadata.is_file() {
+		let file_name = location.file_name().unwrap_or_default().to_string_lossy();
 		if file_name.starts_with("client_secret") && file_name.ends_with(".json") {
-			let path = unknown.to_str().unwrap();
-			let path = path.to_string();
-			result.push(path);
-			return Ok(result);
+			result.push(location.to_path_buf());
 		}
-	} else if unknown.is_dir() {
-		for entry in std::fs::read_dir(unknown)? {
-			let entry = entry?;
-			let path = entry.path();
-			let mut tmp = enumerate_client_secret(path.to_str().unwrap())?;
+	} else if metadata.is_dir() {
+		let mut entries = vec![];
+		for entry in std::fs::read_dir(location)? {
+			entries.push(entry?.path());
+		}
+		entries.sort();
+		for path in entries {
+			let is_dir = path.is_dir();
+			if is_dir {
+				let name = path.file_name().unwrap_or_default().to_string_lossy();
+				if depth == 0 || is_skipped_directory(&name) {
+					continue;
+				}
+			}
+			let mut tmp = enumerate_client_secret(&path, if is_dir { depth - 1 } else { depth })?;
 			result.append(&mut tmp);
 		}
-		return Ok(result);
 	}
 	return Ok(result);
 }
@@ -56,21 +208,47 @@ fn enumerate_client_secret(location: &str) -> Result<Vec<String>, Box<dyn std::e
 ///
 /// コンフィギュレーションを行います。
 ///
-pub fn configure() -> Result<ClientSecret, Box<dyn std::error::Error>> {
-	// カレントディレクトリ配下の client_secret*.json を検索
-	let files = enumerate_client_secret(".")?;
-	if files.len() == 0 {
-		return Err("client secret がみつかりません。".into());
+/// 次の順で client secret を探します。
+/// 1. `--client-secret` で指定されたファイル
+/// 2. 環境変数 `GOOGLE_CLIENT_SECRET_FILE` で指定されたファイル
+/// 3. 環境変数 `GOOGLE_CLIENT_SECRET_JSON` の内容
+/// 4. カレントディレクトリ配下の client_secret*.json
+/// 5. 設定ディレクトリ配下の client_secret*.json
+///
+/// # Arguments
+/// * `client_secret_path` - 明示的に指定されたファイルパス
+///
+pub fn configure(client_secret_path: Option<&str>) -> Result<Credentials, Box<dyn std::error::Error>> {
+	if let Some(path) = client_secret_path {
+		return parse_client_secret(std::path::Path::new(path));
 	}
 
-	for file in files {
-		let result = parse_client_secret(&file);
-		if result.is_err() {
-			info!("パースエラー {:?}", file);
+	if let Some(path) = std::env::var_os(CLIENT_SECRET_FILE_ENV).filter(|s| !s.is_empty()) {
+		return parse_client_secret(std::path::Path::new(&path));
+	}
+
+	if let Ok(json) = std::env::var(CLIENT_SECRET_JSON_ENV) {
+		if !json.is_empty() {
+			return parse_client_secret_json(&json)
+				.map_err(|e| format!("{} を解析できません。理由: {}", CLIENT_SECRET_JSON_ENV, e).into());
+		}
+	}
+
+	for location in get_search_locations() {
+		let files = enumerate_client_secret(&location, MAX_SEARCH_DEPTH)?;
+		if files.is_empty() {
 			continue;
 		}
-		// パースに成功した最初のファイルを採用
-		return Ok(result.unwrap());
+		if 1 < files.len() {
+			let candidates: Vec<String> = files.iter().map(|f| format!("  {}", f.display())).collect();
+			return Err(format!(
+				"client secret が複数みつかりました。--client-secret で使用するファイルを指定してください。\n{}",
+				candidates.join("\n")
+			)
+			.into());
+		}
+		info!("client secret: {}", files[0].display());
+		return parse_client_secret(&files[0]);
 	}
 
 	return Err("client secret がみつかりません。".into());
@@ -82,15 +260,53 @@ pub fn configure() -> Result<ClientSecret, Box<dyn std::error::Error>> {
 /// # Arguments
 /// * `path` - ファイルパス
 ///
-fn parse_client_secret(path: &str) -> Result<ClientSecret, Box<dyn std::error::Error>> {
-	let file = std::fs::File::open(path)?;
-	let reader = std::io::BufReader::new(file);
-	let client_secret: ClientSecret = serde_json::from_reader(reader)?;
-	if client_secret.installed.client_id.is_empty() {
+pub fn parse_client_secret(path: &std::path::Path) -> Result<Credentials, Box<dyn std::error::Error>> {
+	let text =
+		std::fs::read_to_string(path).map_err(|e| format!("{} を開けません。理由: {}", path.display(), e))?;
+	let client_secret = parse_client_secret_json(&text)
+		.map_err(|e| format!("パースエラー {} 理由: {}", path.display(), e))?;
+	return Ok(client_secret);
+}
+
+///
+/// client_secret*.json の内容をパースします。
+///
+/// "installed", "web" と、gcloud の "authorized_user" 形式を受け付けます。
+///
+/// # Arguments
+/// * `json` - JSON 文字列
+///
+fn parse_client_secret_json(json: &str) -> Result<Credentials, Box<dyn std::error::Error>> {
+	let value: serde_json::Value = serde_json::from_str(json)?;
+
+	if value.get("type").and_then(|t| t.as_str()) == Some("authorized_user") {
+		let user: AuthorizedUser = serde_json::from_value(value)?;
+		if user.refresh_token.is_empty() {
+			return Err("無効な refresh token です。".into());
+		}
+		validate_client(&user.client_id, &user.client_secret)?;
+		return Ok(Credentials::AuthorizedUser(user));
+	}
+
+	let client_secret: ClientSecret = serde_json::from_value(value)?;
+	let client = client_secret.client();
+	if client.is_none() {
+		return Err("\"installed\" または \"web\" がみつかりません。".into());
+	}
+	let client = client.unwrap();
+	validate_client(&client.client_id, &client.client_secret)?;
+	return Ok(Credentials::ClientSecret(Box::new(client_secret)));
+}
+
+///
+/// client id と client secret を検証します。
+///
+fn validate_client(client_id: &str, client_secret: &str) -> Result<(), Box<dyn std::error::Error>> {
+	if client_id.is_empty() {
 		return Err("無効な client id です。".into());
 	}
-	if client_secret.installed.client_secret.is_empty() {
+	if client_secret.is_empty() {
 		return Err("無効な client secret です。".into());
 	}
-	return Ok(client_secret);
+	return Ok(());
 }
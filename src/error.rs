@@ -0,0 +1,140 @@
+//!
+//! Google OAuth 2.0 の手続きで発生するエラー
+//!
+//! `Box<dyn std::error::Error>` として返されるため、呼び出し側では
+//! `downcast_ref::<OAuth2Error>()` で種類を判別できます。
+
+///
+/// Google OAuth 2.0 の手続きで発生するエラー
+///
+#[derive(Debug)]
+pub enum OAuth2Error {
+	/// トークンエンドポイントが返したエラー
+	TokenEndpoint {
+		/// "invalid_grant", "authorization_pending" など
+		error: String,
+		/// error_description
+		description: String,
+	},
+	/// 認可リクエストのパラメーターが正しくない
+	InvalidAuthorizationOptions(String),
+	/// ID トークンが正しくない (署名、発行者、aud、有効期限など)
+	InvalidIdToken(String),
+	/// 許可されていないドメインのアカウント
+	DomainNotAllowed {
+		/// ID トークンの hd クレーム (個人のアカウントでは None)
+		hd: Option<String>,
+		/// メールアドレス
+		email: Option<String>,
+	},
+	/// メールアドレスが確認されていないアカウント
+	EmailNotVerified(Option<String>),
+	/// リダイレクトで受け取った state が、認可リクエストのものと一致しない
+	StateMismatch,
+	/// ID トークンの nonce が、認可リクエストのものと一致しない
+	NonceMismatch,
+	/// ID トークンの nonce が、既に使用されている (リプレイ)
+	NonceReplayed,
+	/// アクセストークンの aud が、想定するクライアント ID と一致しない
+	AudienceMismatch {
+		/// 想定する値
+		expected: String,
+		/// 実際の値
+		actual: String,
+	},
+	/// アクセストークンの azp が、想定するクライアント ID と一致しない
+	AuthorizedPartyMismatch {
+		/// 想定する値
+		expected: String,
+		/// 実際の値
+		actual: String,
+	},
+	/// アクセストークンに必要なスコープが付与されていない
+	MissingScopes(Vec<String>),
+	/// アクセストークンの残りの有効期間が足りない
+	InsufficientLifetime {
+		/// 残りの有効期間 (秒)
+		remaining_seconds: i64,
+		/// 必要な有効期間 (秒)
+		required_seconds: i64,
+	},
+}
+
+impl OAuth2Error {
+	///
+	/// トークンエンドポイントが返したエラーコードを返します。
+	///
+	pub fn token_error(&self) -> Option<&str> {
+		return match self {
+			OAuth2Error::TokenEndpoint { error, .. } => Some(error),
+			_ => None,
+		};
+	}
+
+	///
+	/// 更新トークンが無効 (失効、取り消し、ローテーション済み) であることを示すエラーかどうかを返します。
+	///
+	pub fn is_invalid_grant(&self) -> bool {
+		return self.token_error() == Some("invalid_grant");
+	}
+}
+
+impl std::fmt::Display for OAuth2Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			OAuth2Error::TokenEndpoint { error, description } => {
+				write!(f, "トークンを取得できません。理由: {} {}", error, description)
+			}
+			OAuth2Error::InvalidAuthorizationOptions(reason) => {
+				write!(
+					f,
+					"認可リクエストのパラメーターが正しくありません。理由: {}",
+					reason
+				)
+			}
+			OAuth2Error::InvalidIdToken(reason) => {
+				write!(f, "ID トークンが正しくありません。理由: {}", reason)
+			}
+			OAuth2Error::DomainNotAllowed { hd, email } => write!(
+				f,
+				"許可されていないドメインのアカウントです。[hd: {}, email: {}]",
+				hd.as_deref().unwrap_or("-"),
+				email.as_deref().unwrap_or("-")
+			),
+			OAuth2Error::EmailNotVerified(email) => write!(
+				f,
+				"メールアドレスが確認されていないアカウントです。[{}]",
+				email.as_deref().unwrap_or("-")
+			),
+			OAuth2Error::StateMismatch => write!(f, "state が一致しません。"),
+			OAuth2Error::NonceMismatch => write!(f, "ID トークンの nonce が一致しません。"),
+			OAuth2Error::NonceReplayed => write!(f, "ID トークンの nonce は既に使用されています。"),
+			OAuth2Error::AudienceMismatch { expected, actual } => {
+				write!(f, "aud が一致しません。[想定: {}, 実際: {}]", expected, actual)
+			}
+			OAuth2Error::AuthorizedPartyMismatch { expected, actual } => {
+				write!(f, "azp が一致しません。[想定: {}, 実際: {}]", expected, actual)
+			}
+			OAuth2Error::MissingScopes(scopes) => {
+				write!(f, "必要なスコープが付与されていません。[{}]", scopes.join(" "))
+			}
+			OAuth2Error::InsufficientLifetime {
+				remaining_seconds,
+				required_seconds,
+			} => write!(
+				f,
+				"アクセストークンの残りの有効期間が足りません。[残り: {}秒, 必要: {}秒]",
+				remaining_seconds, required_seconds
+			),
+		};
+	}
+}
+
+impl std::error::Error for OAuth2Error {}
+
+///
+/// エラーが `OAuth2Error` であれば、それを返します。
+///
+pub fn as_oauth2_error<'a>(error: &'a (dyn std::error::Error + 'static)) -> Option<&'a OAuth2Error> {
+	return error.downcast_ref::<OAuth2Error>();
+}
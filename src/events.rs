@@ -0,0 +1,270 @@
+//!
+//! トークンのライフサイクルのイベント
+//!
+//! 認可、更新、無効化などで資格情報が変わったことを、登録された [`TokenEventObserver`] に通知します。
+//! イベントにはトークンそのものを含めず、指紋 (SHA256 の先頭) だけを含めます。
+
+use crate::gauth2::TokenData;
+use crate::{id_token, util};
+
+///
+/// イベントの種類
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenEventKind {
+	/// 認可手続きでトークンを受け取りました。
+	Authorized,
+	/// 更新トークンでアクセストークンを更新しました。
+	Refreshed,
+	/// トークンを無効にしました。
+	Revoked,
+	/// 更新トークンが無効になっていました (invalid_grant)。
+	InvalidGrant,
+}
+
+impl TokenEventKind {
+	///
+	/// 記録に使う名前を返します。
+	///
+	pub fn as_str(&self) -> &'static str {
+		return match self {
+			TokenEventKind::Authorized => "authorized",
+			TokenEventKind::Refreshed => "refreshed",
+			TokenEventKind::Revoked => "revoked",
+			TokenEventKind::InvalidGrant => "invalid_grant",
+		};
+	}
+
+	///
+	/// 失敗を示すイベントかどうかを返します。
+	///
+	pub fn is_failure(&self) -> bool {
+		return *self == TokenEventKind::InvalidGrant;
+	}
+}
+
+impl std::fmt::Display for TokenEventKind {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return write!(f, "{}", self.as_str());
+	}
+}
+
+///
+/// イベントの対象のアカウント (ID トークンのクレーム)
+///
+#[derive(Debug, Clone, Default)]
+pub struct TokenAccount {
+	/// ユーザー ID
+	pub sub: Option<String>,
+	/// メールアドレス
+	pub email: Option<String>,
+	/// 署名を確認した ID トークンのクレームかどうか
+	///
+	/// デバイスフローや保存先から読み込んだ ID トークンは、署名を確認していない場合があります。
+	pub verified: bool,
+}
+
+///
+/// トークンのライフサイクルのイベント
+///
+#[derive(Debug, Clone)]
+pub struct TokenEvent {
+	/// 種類
+	pub kind: TokenEventKind,
+	/// 発生した日時
+	pub occurred_at: chrono::DateTime<chrono::Utc>,
+	/// クライアント ID
+	pub client_id: String,
+	/// アクセストークンの指紋
+	pub access_token_fingerprint: Option<String>,
+	/// 更新トークンの指紋
+	pub refresh_token_fingerprint: Option<String>,
+	/// 付与されたスコープ
+	pub scopes: Vec<String>,
+	/// アカウント (ID トークンが無ければ空)
+	pub account: TokenAccount,
+	/// アクセストークンの有効期限
+	pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl TokenEvent {
+	///
+	/// 資格情報からイベントを作ります。
+	///
+	/// # Arguments
+	/// * `kind` - 種類
+	/// * `client_id` - クライアント ID
+	/// * `token_data` - イベントの対象の資格情報
+	/// * `id_token_verified` - `token_data` の ID トークンの署名を確認したかどうか
+	///
+	pub fn new(
+		kind: TokenEventKind,
+		client_id: &str,
+		token_data: &TokenData,
+		id_token_verified: bool,
+	) -> Self {
+		// 署名の確認は呼び出し側が行い、ここではクレームを取り出して、確認したかどうかを記録するだけです。
+		let account = match token_data.id_token().map(id_token::decode_unverified) {
+			Some(Ok(decoded)) => TokenAccount {
+				sub: Some(decoded.claims.sub),
+				email: decoded.claims.email,
+				verified: id_token_verified,
+			},
+			_ => TokenAccount::default(),
+		};
+		return Self {
+			kind,
+			occurred_at: chrono::Utc::now(),
+			client_id: client_id.to_string(),
+			access_token_fingerprint: Some(token_data.access_token.as_str())
+				.filter(|t| !t.is_empty())
+				.map(util::token_fingerprint),
+			refresh_token_fingerprint: token_data.refresh_token().map(util::token_fingerprint),
+			scopes: token_data.scopes().into_iter().map(|s| s.to_string()).collect(),
+			account,
+			expires_at: token_data.expires_at(),
+		};
+	}
+}
+
+///
+/// イベントを受け取るもの
+///
+/// 通知は、イベントを発生させたスレッドで同期的に行います。時間のかかる処理は別のスレッドに渡してください。
+///
+pub trait TokenEventObserver: Send {
+	///
+	/// イベントを受け取ります。
+	///
+	fn on_event(&self, event: &TokenEvent);
+}
+
+///
+/// 共有されたイベントを受け取るもの
+///
+/// `Arc<AuditLog>` などを `GoogleOAuth2` に渡した後も、呼び出し側で使用できるようにします。
+///
+impl<T: TokenEventObserver + Sync + ?Sized> TokenEventObserver for std::sync::Arc<T> {
+	fn on_event(&self, event: &TokenEvent) {
+		(**self).on_event(event);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gauth2::tests::mock_service;
+	use crate::id_token::tests::{sign, test_jwks, valid_claims};
+
+	/// 受け取ったイベントを記録するもの
+	#[derive(Default)]
+	struct Recorder {
+		events: std::sync::Mutex<Vec<TokenEvent>>,
+	}
+
+	impl TokenEventObserver for Recorder {
+		fn on_event(&self, event: &TokenEvent) {
+			self.events.lock().unwrap().push(event.clone());
+		}
+	}
+
+	impl Recorder {
+		fn take(&self) -> Vec<TokenEvent> {
+			return std::mem::take(&mut *self.events.lock().unwrap());
+		}
+	}
+
+	/// ID トークンを含む、更新のレスポンスを返します。
+	fn refresh_response(access_token: &str) -> String {
+		return serde_json::json!({
+			"access_token": access_token,
+			"expires_in": 3599,
+			"scope": "openid https://www.googleapis.com/auth/userinfo.email",
+			"id_token": sign(&valid_claims()),
+		})
+		.to_string();
+	}
+
+	#[test]
+	fn refresh_emits_fingerprints_and_an_unverified_account() {
+		let (mut service, transport) = mock_service();
+		let recorder = std::sync::Arc::new(Recorder::default());
+		service.add_observer(Box::new(recorder.clone()));
+		service.set_refresh_token("rt");
+		transport.push_response(200, &refresh_response("at1"));
+
+		service.refresh_access_token().unwrap();
+
+		let events = recorder.take();
+		assert_eq!(events.len(), 1);
+		let event = &events[0];
+		assert_eq!(event.kind, TokenEventKind::Refreshed);
+		assert_eq!(event.client_id, "client-id");
+		assert_eq!(
+			event.access_token_fingerprint.as_deref(),
+			Some(util::token_fingerprint("at1").as_str())
+		);
+		assert_eq!(
+			event.refresh_token_fingerprint.as_deref(),
+			Some(util::token_fingerprint("rt").as_str())
+		);
+		assert_eq!(event.scopes.len(), 2);
+		assert!(event.expires_at.is_some());
+		assert_eq!(event.account.sub.as_deref(), Some("1234567890"));
+		assert_eq!(event.account.email.as_deref(), Some("user@example.com"));
+		assert!(!event.account.verified);
+	}
+
+	#[test]
+	fn marks_the_account_verified_after_checking_the_signature() {
+		let (mut service, transport) = mock_service();
+		let recorder = std::sync::Arc::new(Recorder::default());
+		service.add_observer(Box::new(recorder.clone()));
+		service.set_refresh_token("rt");
+		transport.push_response(200, &refresh_response("at1"));
+		service.refresh_access_token().unwrap();
+		recorder.take();
+
+		transport.push_response(200, &test_jwks_json());
+		let id_token = service.token_data().id_token().unwrap().to_string();
+		service.verify_id_token(&id_token).unwrap();
+		transport.push_response(200, "");
+		service.revoke().unwrap();
+
+		let events = recorder.take();
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].kind, TokenEventKind::Revoked);
+		assert!(events[0].account.verified);
+		assert_eq!(service.token_data().refresh_token(), None);
+	}
+
+	#[test]
+	fn invalid_grant_is_emitted_before_the_credentials_are_evicted() {
+		let (mut service, transport) = mock_service();
+		let recorder = std::sync::Arc::new(Recorder::default());
+		service.add_observer(Box::new(recorder.clone()));
+		service.set_refresh_token("revoked");
+		transport.push_response(400, r#"{"error":"invalid_grant"}"#);
+
+		service.refresh_access_token().unwrap_err();
+
+		let events = recorder.take();
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].kind, TokenEventKind::InvalidGrant);
+		assert!(events[0].kind.is_failure());
+		assert_eq!(
+			events[0].refresh_token_fingerprint.as_deref(),
+			Some(util::token_fingerprint("revoked").as_str())
+		);
+	}
+
+	/// テスト用の鍵の JWKS を、jwks_uri のレスポンスとして返します。
+	fn test_jwks_json() -> String {
+		let keys: Vec<serde_json::Value> = test_jwks()
+			.keys
+			.iter()
+			.map(|k| serde_json::json!({"kid": k.kid, "kty": k.kty, "alg": k.alg, "n": k.n, "e": k.e}))
+			.collect();
+		return serde_json::json!({ "keys": keys }).to_string();
+	}
+}
@@ -1,65 +1,410 @@
-use std::io::{BufRead, Write};
+use std::io::Write;
 
-use crate::{error, info, util};
+use crate::error::{as_oauth2_error, OAuth2Error};
+use crate::events::{TokenEvent, TokenEventKind, TokenEventObserver};
+use crate::id_token::{IdTokenClaims, Jwks, NonceStore};
+use crate::lock::CredentialLock;
+use crate::retry::RetryPolicy;
+use crate::store::CredentialStore;
+use crate::transport::{HttpRequest, HttpResponse, HttpTransport};
+use crate::verification::VerificationPolicy;
+use crate::{authorization, configuration, id_token, transport, util};
+use log::{debug, error, info, warn};
 
-#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone, Default)]
 pub struct TokenData {
 	/// アクセストークン
 	pub access_token: String,
 	/// アクセス トークンの残りの有効期間（秒）
+	#[serde(default)]
 	expires_in: u32,
 	/// このプロパティは、リクエストに ID スコープ（openid、profile、email など）が含まれる場合にのみ返されます。
+	#[serde(default)]
 	id_token: Option<String>,
-	/// 更新トークン
-	refresh_token: String,
+	/// 更新トークン。再同意の場合や、更新の応答には含まれません。
+	#[serde(default)]
+	refresh_token: Option<String>,
 	/// access_token によって付与されるアクセス スコープ
+	#[serde(default)]
 	scope: String,
 	/// 常に Bearer
+	#[serde(default)]
 	token_type: String,
+	/// トークンを受け取った日時
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	issued_at: Option<chrono::DateTime<chrono::Utc>>,
+	/// アクセストークンの有効期限
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	expires_at: Option<chrono::DateTime<chrono::Utc>>,
+	/// 更新トークンを受け取った日時
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	refresh_token_issued_at: Option<chrono::DateTime<chrono::Utc>>,
+	/// トークンを発行したクライアント ID (保存するときに記録します)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	client_id: Option<String>,
 }
 
-/// アクセストークン情報
-#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
-pub struct TokenVerificationResult {
+impl TokenData {
+	/// 受け取った日時を記録し、expires_in から有効期限を求めます。
+	fn stamp(&mut self, now: chrono::DateTime<chrono::Utc>) {
+		self.issued_at = Some(now);
+		self.expires_at = Some(now + chrono::Duration::seconds(self.expires_in as i64));
+		if self.refresh_token.is_some() {
+			self.refresh_token_issued_at = Some(now);
+		}
+	}
+
+	/// 新しく受け取ったトークンで置き換えます。
 	///
-	access_type: String,
+	/// 新しいトークンに含まれない更新トークン、ID トークン、スコープは、現在の値を引き継ぎます。
+	/// 新しい更新トークンが含まれる場合 (ローテーション) は、そちらに置き換えます。
+	fn merge(&mut self, newer: TokenData) {
+		let previous = std::mem::replace(self, newer);
+		if self.refresh_token.is_none() {
+			self.refresh_token = previous.refresh_token;
+			self.refresh_token_issued_at = previous.refresh_token_issued_at;
+		} else if previous.refresh_token.is_some() && self.refresh_token != previous.refresh_token {
+			info!("更新トークンが新しいものに置き換えられました。");
+		}
+		if self.id_token.is_none() {
+			self.id_token = previous.id_token;
+		}
+		if self.scope.is_empty() {
+			self.scope = previous.scope;
+		}
+	}
+
+	/// 更新トークンを返します。
+	pub fn refresh_token(&self) -> Option<&str> {
+		return self.refresh_token.as_deref();
+	}
+
+	/// ID トークンを返します。
+	pub fn id_token(&self) -> Option<&str> {
+		return self.id_token.as_deref();
+	}
+
+	/// 付与されたスコープを返します。
+	pub fn scopes(&self) -> Vec<&str> {
+		return self.scope.split_whitespace().collect();
+	}
+
+	/// 更新トークンを受け取った日時を返します。分からない場合は None を返します。
+	pub fn refresh_token_issued_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+		return self.refresh_token_issued_at;
+	}
+
+	/// すべてのトークンを破棄します。
+	fn clear(&mut self) {
+		*self = TokenData::default();
+	}
+
+	/// トークンを受け取った日時を返します。
+	pub fn issued_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+		return self.issued_at;
+	}
+
+	/// アクセストークンの有効期限を返します。
+	pub fn expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+		return self.expires_at;
+	}
+
+	/// アクセストークンの有効期限が切れているかどうかを返します。
 	///
-	aud: String,
+	/// アクセストークンが無い場合や、有効期限が分からない場合も true を返します。
+	pub fn is_expired(&self) -> bool {
+		return self.expires_within(chrono::Duration::zero());
+	}
+
+	/// 指定した時間のうちに、アクセストークンの有効期限が切れるかどうかを返します。
 	///
-	azp: String,
-	/// メールアドレス
-	email: String,
+	/// # Arguments
+	/// * `duration` - 時間 (クロックのずれに対する余裕)
+	pub fn expires_within(&self, duration: chrono::Duration) -> bool {
+		if self.access_token.is_empty() {
+			return true;
+		}
+		return match self.expires_at {
+			Some(expires_at) => expires_at <= chrono::Utc::now() + duration,
+			None => true,
+		};
+	}
+}
+
+/// アクセストークン情報 (tokeninfo の結果)
+///
+/// スコープによって含まれないクレームがあるため、すべて省略可能です。
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone)]
+pub struct TokenVerificationResult {
+	/// online または offline
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	access_type: Option<String>,
+	/// 対象のクライアント ID
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	aud: Option<String>,
+	/// 認可を求めたクライアント ID
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	azp: Option<String>,
+	/// メールアドレス (email スコープの場合のみ)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	email: Option<String>,
 	/// ユーザーのメールアドレスが確認済みであれば true、そうでない場合は false。
-	email_verified: String,
-	///
-	exp: String,
+	#[serde(
+		default,
+		deserialize_with = "util::deserialize_string_or_bool",
+		skip_serializing_if = "Option::is_none"
+	)]
+	email_verified: Option<bool>,
+	/// 有効期限 (UNIX 時刻)
+	#[serde(
+		default,
+		deserialize_with = "util::deserialize_string_or_i64",
+		skip_serializing_if = "Option::is_none"
+	)]
+	exp: Option<i64>,
 	/// アクセス トークンの残りの有効期間（秒）
-	expires_in: String,
+	#[serde(
+		default,
+		deserialize_with = "util::deserialize_string_or_i64",
+		skip_serializing_if = "Option::is_none"
+	)]
+	expires_in: Option<i64>,
 	/// access_token によって付与されるアクセス スコープ
-	scope: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	scope: Option<String>,
 	/// ユーザー ID。すべての Google アカウントの中で一意であり、再利用されることはありません。
-	sub: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	sub: Option<String>,
+	/// その他のクレーム
+	#[serde(flatten)]
+	extra: serde_json::Map<String, serde_json::Value>,
 }
 
-/// ユーザープロファイル
-#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+impl TokenVerificationResult {
+	/// access_type を返します。
+	pub fn access_type(&self) -> Option<&str> {
+		return self.access_type.as_deref();
+	}
+
+	/// aud を返します。
+	pub fn aud(&self) -> Option<&str> {
+		return self.aud.as_deref();
+	}
+
+	/// azp を返します。
+	pub fn azp(&self) -> Option<&str> {
+		return self.azp.as_deref();
+	}
+
+	/// メールアドレスを返します。
+	pub fn email(&self) -> Option<&str> {
+		return self.email.as_deref();
+	}
+
+	/// メールアドレスが確認済みかどうかを返します。
+	pub fn email_verified(&self) -> Option<bool> {
+		return self.email_verified;
+	}
+
+	/// 有効期限を返します。
+	pub fn exp(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+		use chrono::TimeZone;
+
+		return chrono::Utc.timestamp_opt(self.exp?, 0).single();
+	}
+
+	/// アクセストークンの残りの有効期間 (秒) を返します。
+	pub fn expires_in(&self) -> Option<i64> {
+		return self.expires_in;
+	}
+
+	/// 付与されたスコープを返します。
+	pub fn scopes(&self) -> Vec<&str> {
+		return self
+			.scope
+			.as_deref()
+			.unwrap_or_default()
+			.split_whitespace()
+			.collect();
+	}
+
+	/// ユーザー ID を返します。
+	pub fn sub(&self) -> Option<&str> {
+		return self.sub.as_deref();
+	}
+
+	/// その他のクレームを返します。
+	pub fn extra(&self) -> &serde_json::Map<String, serde_json::Value> {
+		return &self.extra;
+	}
+}
+
+/// ユーザープロファイル (userinfo の結果)
+///
+/// アカウントやスコープによって含まれないクレームがあるため、sub 以外は省略可能です。
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone)]
 pub struct UserProfile {
 	/// メールアドレス
-	email: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	email: Option<String>,
 	/// ユーザーのメールアドレスが確認済みであれば true、そうでない場合は false。
-	email_verified: bool,
+	#[serde(
+		default,
+		deserialize_with = "util::deserialize_string_or_bool",
+		skip_serializing_if = "Option::is_none"
+	)]
+	email_verified: Option<bool>,
 	/// ユーザーの姓（ラストネーム）
-	family_name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	family_name: Option<String>,
 	/// ユーザーの名（ファースト ネーム）
-	given_name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	given_name: Option<String>,
+	/// Google Workspace のドメイン
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	hd: Option<String>,
 	/// ユーザーの言語 / 地域
+	#[serde(default, skip_serializing_if = "Option::is_none")]
 	locale: Option<String>,
 	/// ユーザーの氏名（表示可能な形式）
-	name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	name: Option<String>,
 	/// ユーザーのプロフィール写真の URL
-	picture: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	picture: Option<String>,
 	/// ユーザー ID。すべての Google アカウントの中で一意であり、再利用されることはありません。
 	sub: String,
+	/// その他のクレーム
+	#[serde(flatten)]
+	extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl UserProfile {
+	/// メールアドレスを返します。
+	pub fn email(&self) -> Option<&str> {
+		return self.email.as_deref();
+	}
+
+	/// メールアドレスが確認済みかどうかを返します。
+	pub fn email_verified(&self) -> Option<bool> {
+		return self.email_verified;
+	}
+
+	/// 姓を返します。
+	pub fn family_name(&self) -> Option<&str> {
+		return self.family_name.as_deref();
+	}
+
+	/// 名を返します。
+	pub fn given_name(&self) -> Option<&str> {
+		return self.given_name.as_deref();
+	}
+
+	/// Google Workspace のドメインを返します。
+	pub fn hd(&self) -> Option<&str> {
+		return self.hd.as_deref();
+	}
+
+	/// 言語 / 地域を返します。
+	pub fn locale(&self) -> Option<&str> {
+		return self.locale.as_deref();
+	}
+
+	/// 氏名を返します。
+	pub fn name(&self) -> Option<&str> {
+		return self.name.as_deref();
+	}
+
+	/// プロフィール写真の URL を返します。
+	pub fn picture(&self) -> Option<&str> {
+		return self.picture.as_deref();
+	}
+
+	/// ユーザー ID を返します。
+	pub fn sub(&self) -> &str {
+		return &self.sub;
+	}
+
+	/// その他のクレームを返します。
+	pub fn extra(&self) -> &serde_json::Map<String, serde_json::Value> {
+		return &self.extra;
+	}
+}
+
+/// クロックのずれに対する既定の余裕 (秒)。有効期限のこの時間前に、アクセストークンを更新します。
+const DEFAULT_CLOCK_SKEW_SECONDS: i64 = 60;
+
+/// 公開ステータスが「テスト」のアプリケーションに発行される更新トークンの有効期間 (日)
+pub const TESTING_MODE_REFRESH_TOKEN_LIFETIME_DAYS: i64 = 7;
+
+/// 更新トークンの有効期限が近いことを警告する時間 (時間)
+const REFRESH_TOKEN_EXPIRY_WARNING_HOURS: i64 = 24;
+
+/// デバイスフローのポーリング間隔の既定値 (秒)
+const DEFAULT_DEVICE_POLLING_INTERVAL_SECONDS: u64 = 5;
+
+/// 認可手続きの方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoginMethod {
+	/// ブラウザーが使えればブラウザーを、使えなければ (SSH 接続など) デバイスフローを使用します。
+	#[default]
+	Auto,
+	/// ブラウザーを開き、ローカルサーバーでリダイレクトを受け取ります。
+	Browser,
+	/// 別の端末で URL を開き、表示されたコードを入力します。
+	Device,
+}
+
+impl std::str::FromStr for LoginMethod {
+	type Err = Box<dyn std::error::Error>;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		return match s {
+			"auto" => Ok(LoginMethod::Auto),
+			"browser" => Ok(LoginMethod::Browser),
+			"device" => Ok(LoginMethod::Device),
+			_ => Err(format!("不明な認可手続きの方法です。[{}]", s).into()),
+		};
+	}
+}
+
+/// デバイス認可エンドポイントのレスポンス
+#[derive(serde_derive::Deserialize, Debug)]
+struct DeviceAuthorizationResponse {
+	/// トークンエンドポイントに送るコード
+	device_code: String,
+	/// ユーザーが入力するコード
+	user_code: String,
+	/// ユーザーが開く URL (Google は verification_url を返します)
+	#[serde(alias = "verification_uri")]
+	verification_url: String,
+	/// device_code の有効期間 (秒)
+	expires_in: u64,
+	/// ポーリング間隔 (秒)
+	#[serde(default)]
+	interval: Option<u64>,
+}
+
+/// tokeninfo エンドポイントの既定値 (discovery には含まれません)
+pub const DEFAULT_TOKENINFO_ENDPOINT: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+/// tokeninfo で確認するトークンの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+	/// アクセストークン
+	AccessToken,
+	/// ID トークン
+	IdToken,
+}
+
+impl TokenKind {
+	/// tokeninfo のパラメーター名を返します。
+	pub fn parameter_name(&self) -> &'static str {
+		return match self {
+			TokenKind::AccessToken => "access_token",
+			TokenKind::IdToken => "id_token",
+		};
+	}
 }
 
 /// Google OAuth 2.0 の設定 URL を取得します。
@@ -77,6 +422,9 @@ struct WellKnownEndpoints {
 	/// https://www.googleapis.com/oauth2/v3/userinfo
 	userinfo_endpoint: String,
 	revocation_endpoint: String,
+	/// https://oauth2.googleapis.com/device/code
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	device_authorization_endpoint: Option<String>,
 	jwks_uri: String,
 	response_types_supported: Vec<String>,
 	subject_types_supported: Vec<String>,
@@ -87,37 +435,117 @@ struct WellKnownEndpoints {
 	code_challenge_methods_supported: Vec<String>,
 }
 
+/// レスポンスが 2xx であれば本文を返します。
+fn get_response_text(response: HttpResponse) -> Result<String, Box<dyn std::error::Error>> {
+	if !response.is_success() {
+		return Err(format!("HTTP {} {}", response.status, response.body).into());
+	}
+	return Ok(response.body);
+}
+
+/// トークンエンドポイントのレスポンスを解析します。
+fn parse_token_response(response: HttpResponse) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+	let value: serde_json::Value = serde_json::from_str(&response.body)
+		.map_err(|_| format!("HTTP {} {}", response.status, response.body))?;
+	if let Some(error) = value.get("error") {
+		let error = error.as_str().unwrap_or_default();
+		let description = value
+			.get("error_description")
+			.and_then(|d| d.as_str())
+			.unwrap_or_default();
+		return Err(Box::new(OAuth2Error::TokenEndpoint {
+			error: error.to_string(),
+			description: description.to_string(),
+		}));
+	}
+	if !response.is_success() {
+		return Err(format!("HTTP {} {}", response.status, response.body).into());
+	}
+	return Ok(value);
+}
+
 /// Google OAuth 2.0 の設定を取得します。
-fn get_gauth_wellknown_endpoints() -> Result<WellKnownEndpoints, Box<dyn std::error::Error>> {
+fn get_gauth_wellknown_endpoints(
+	transport: &dyn HttpTransport,
+	retry_policy: &RetryPolicy,
+) -> Result<WellKnownEndpoints, Box<dyn std::error::Error>> {
 	let url = get_wellknown_schema_url();
-	let text = util::http_get(&url)?;
+	let response = retry_policy.send(transport, &HttpRequest::get(&url))?;
+	let text = get_response_text(response)?;
 
 	let result: WellKnownEndpoints = serde_json::from_str(&text)?;
 
 	return Ok(result);
 }
 
+/// tokeninfo エンドポイントでトークンを確認します。
+///
+/// # Arguments
+/// * `transport` - HTTP 通信を行うもの
+/// * `retry_policy` - 再試行の方針
+/// * `endpoint` - tokeninfo エンドポイント
+/// * `kind` - トークンの種類
+/// * `token` - トークン
+pub fn query_tokeninfo(
+	transport: &dyn HttpTransport,
+	retry_policy: &RetryPolicy,
+	endpoint: &str,
+	kind: TokenKind,
+	token: &str,
+) -> Result<TokenVerificationResult, Box<dyn std::error::Error>> {
+	let url = format!(
+		"{}?{}={}",
+		endpoint,
+		kind.parameter_name(),
+		util::urlencode(token)
+	);
+	let response = retry_policy.send(transport, &HttpRequest::get(&url))?;
+	let text = get_response_text(response)?;
+
+	let token_info: TokenVerificationResult = serde_json::from_str(&text)?;
+
+	return Ok(token_info);
+}
+
+/// Google の公開鍵 (JWKS) を、discovery の jwks_uri から取得します。
+pub fn fetch_google_jwks(
+	transport: &dyn HttpTransport,
+	retry_policy: &RetryPolicy,
+) -> Result<Jwks, Box<dyn std::error::Error>> {
+	let wellknown_endpoints = get_gauth_wellknown_endpoints(transport, retry_policy)?;
+	return id_token::fetch_jwks(transport, retry_policy, &wellknown_endpoints.jwks_uri);
+}
+
 /// 接続を開始します。
+///
+/// code または error を含むリクエストでなければ None を返します。(favicon.ico など)
 fn accept_peer(
 	mut stream: std::net::TcpStream,
-) -> Result<std::collections::HashMap<String, String>, Box<dyn std::error::Error>> {
+) -> Result<Option<util::QueryParameters>, Box<dyn std::error::Error>> {
 	info!("着信あり");
 
-	let buf_reader = std::io::BufReader::new(&mut stream);
-	let http_request: Vec<_> = buf_reader
-		.lines()
-		.map(|result| result.unwrap())
-		.take_while(|line| !line.is_empty())
-		.collect();
+	// listener が non-blocking でも、個々の接続はブロッキングで読み込みます。
+	stream.set_nonblocking(false)?;
+	stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+
+	let mut buf_reader = std::io::BufReader::new(&mut stream);
+	let http_request = util::read_http_request(&mut buf_reader)?;
+	info!("REQUEST> {} {}", http_request.method, http_request.target);
 
 	// リクエスト本文の解析
-	let q = util::diagnose_http_request(&http_request);
+	let q = util::diagnose_http_request(&http_request)?;
 	info!("REQUEST> {}", serde_json::to_string_pretty(&q)?);
 
-	let response = format!("HTTP/1.1 200 OK\r\n\r\nOk.");
-	stream.write(response.as_bytes())?;
+	if !q.contains_key("code") && !q.contains_key("error") {
+		let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+		stream.write_all(response.as_bytes())?;
+		return Ok(None);
+	}
+
+	let response = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nOk.";
+	stream.write_all(response.as_bytes())?;
 
-	return Ok(q);
+	return Ok(Some(q));
 }
 
 /// HTTP サーバーを立ち上げます。
@@ -144,7 +572,7 @@ fn recv_response(port: u16) -> Result<(String, String), Box<dyn std::error::Erro
 	let stop_watch = util::SimpleStopWatch::new();
 
 	info!("リクエストを待機しています...");
-	let mut query = std::collections::HashMap::new();
+	let mut query = util::QueryParameters::default();
 	for status in listener.incoming() {
 		// 120秒で待ち受けを解除
 		if 120 <= stop_watch.elapsed().as_secs() {
@@ -161,14 +589,22 @@ fn recv_response(port: u16) -> Result<(String, String), Box<dyn std::error::Erro
 			break;
 		}
 
-		query = accept_peer(status.unwrap())?;
-
-		break;
+		match accept_peer(status.unwrap()) {
+			Ok(Some(q)) => {
+				query = q;
+				break;
+			}
+			Ok(None) => continue,
+			Err(e) => {
+				error!("リクエストを処理できません。理由: {}", e);
+				continue;
+			}
+		}
 	}
 
 	// 初めに error を取得する
 	let error = query.get_string("error");
-	if error != "" {
+	if !error.is_empty() {
 		return Err(error.into());
 	}
 
@@ -182,11 +618,47 @@ fn recv_response(port: u16) -> Result<(String, String), Box<dyn std::error::Erro
 	return Ok((code, state));
 }
 
+/// アクセストークンを提供するもの
+pub trait TokenProvider {
+	/// 有効なアクセストークンを返します。
+	fn get_access_token(&mut self) -> Result<String, Box<dyn std::error::Error>>;
+}
+
 pub struct GoogleOAuth2 {
+	transport: Box<dyn HttpTransport>,
+	retry_policy: RetryPolicy,
 	wellknown_endpoints: WellKnownEndpoints,
 	client_id: String,
 	client_secret: String,
 	token_data: TokenData,
+	/// クロックのずれに対する余裕
+	clock_skew: chrono::Duration,
+	/// 認可リクエストの追加のパラメーター
+	authorization_options: authorization::AuthorizationOptions,
+	/// 資格情報の保存先
+	credential_store: Option<Box<dyn CredentialStore>>,
+	/// 認可手続きの方法
+	login_method: LoginMethod,
+	/// 更新トークンが無効になった場合に、認可手続きをやり直すかどうか
+	auto_relogin: bool,
+	/// 更新トークンの有効期間 (分かっている場合)
+	refresh_token_lifetime: Option<chrono::Duration>,
+	/// 許可する Google Workspace のドメイン (空であれば制限しません)
+	allowed_domains: Vec<String>,
+	/// Google の公開鍵 (ID トークンの検証用)
+	jwks: Option<Jwks>,
+	/// 使用済みの nonce
+	consumed_nonces: NonceStore,
+	/// アクセストークンの検証方針
+	verification_policy: VerificationPolicy,
+	/// tokeninfo エンドポイント
+	tokeninfo_endpoint: String,
+	/// トークンのライフサイクルのイベントを受け取るもの
+	observers: Vec<Box<dyn TokenEventObserver>>,
+	/// ブラウザーによる認可で使用する、登録済みのリダイレクト URI (None であれば任意のポートを使います)
+	redirect_uri: Option<String>,
+	/// 最後に署名を確認した ID トークン
+	verified_id_token: Option<String>,
 }
 
 impl GoogleOAuth2 {
@@ -194,68 +666,523 @@ impl GoogleOAuth2 {
 	///
 	/// 新しいインスタンスを返します。
 	pub fn new(client_id: &str, client_secret: &str) -> Result<Self, Box<dyn std::error::Error>> {
+		return Self::with_http_options(client_id, client_secret, &transport::HttpClientOptions::default());
+	}
+
+	/// コンストラクター
+	///
+	/// HTTP クライアントの設定 (タイムアウト、プロキシ、ルート証明書など) を指定して、新しいインスタンスを返します。
+	pub fn with_http_options(
+		client_id: &str,
+		client_secret: &str,
+		options: &transport::HttpClientOptions,
+	) -> Result<Self, Box<dyn std::error::Error>> {
+		let transport = transport::ReqwestTransport::with_options(options)?;
+		return Self::with_transport(client_id, client_secret, Box::new(transport));
+	}
+
+	/// コンストラクター
+	///
+	/// HTTP 通信を行うものを指定して、新しいインスタンスを返します。
+	pub fn with_transport(
+		client_id: &str,
+		client_secret: &str,
+		transport: Box<dyn HttpTransport>,
+	) -> Result<Self, Box<dyn std::error::Error>> {
 		// Google OAuth 2.0 の設定を取得します。
-		let wellknown_endpoints = get_gauth_wellknown_endpoints()?;
-		info!(
+		let retry_policy = RetryPolicy::default();
+		let wellknown_endpoints = get_gauth_wellknown_endpoints(transport.as_ref(), &retry_policy)?;
+		debug!(
 			"GOOGLE> wellknown_endpoints: {}",
 			serde_json::to_string_pretty(&wellknown_endpoints)?
 		);
 
 		let instance = Self {
-			wellknown_endpoints: wellknown_endpoints,
+			transport,
+			retry_policy,
+			wellknown_endpoints,
 			client_id: client_id.to_string(),
 			client_secret: client_secret.to_string(),
-			token_data: TokenData {
-				access_token: "".to_string(),
-				expires_in: 0,
-				id_token: None,
-				refresh_token: "".to_string(),
-				scope: "".to_string(),
-				token_type: "".to_string(),
-			},
+			token_data: TokenData::default(),
+			clock_skew: chrono::Duration::seconds(DEFAULT_CLOCK_SKEW_SECONDS),
+			authorization_options: authorization::AuthorizationOptions::default(),
+			credential_store: None,
+			login_method: LoginMethod::default(),
+			auto_relogin: false,
+			refresh_token_lifetime: None,
+			allowed_domains: vec![],
+			jwks: None,
+			consumed_nonces: NonceStore::default(),
+			verification_policy: VerificationPolicy::default(),
+			tokeninfo_endpoint: DEFAULT_TOKENINFO_ENDPOINT.to_string(),
+			observers: vec![],
+			redirect_uri: None,
+			verified_id_token: None,
 		};
 
 		return Ok(instance);
 	}
 
+	/// 認可リクエストの追加のパラメーターを設定します。
+	pub fn set_authorization_options(&mut self, options: authorization::AuthorizationOptions) {
+		self.authorization_options = options;
+	}
+
+	/// 資格情報の保存先を設定します。
+	///
+	/// 認可手続きや更新で受け取ったトークンを保存し、更新トークンが無効になった場合は削除します。
+	pub fn set_credential_store(&mut self, credential_store: Box<dyn CredentialStore>) {
+		self.credential_store = Some(credential_store);
+	}
+
+	/// 保存先から資格情報を読み込みます。
+	///
+	/// 読み込めた場合は true を返します。
+	pub fn load_credentials(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+		let token_data = match &self.credential_store {
+			Some(store) => store.load()?,
+			None => None,
+		};
+		let token_data = match token_data {
+			Some(token_data) => token_data,
+			None => return Ok(false),
+		};
+		info!("保存された資格情報を読み込みました。");
+		self.token_data = token_data;
+		self.check_refresh_token_lifetime();
+		return Ok(true);
+	}
+
+	/// 現在の資格情報を保存先に書き込みます。
+	///
+	/// 他のプロセスが読み込み直すときに確認できるように、クライアント ID を記録します。
+	fn save_credentials(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		self.token_data.client_id = Some(self.client_id.clone());
+		if let Some(store) = &self.credential_store {
+			store.save(&self.token_data)?;
+		}
+		return Ok(());
+	}
+
+	/// 他のプロセスと共有する保存先であれば、読み込み、更新、書き込みの間のロックを取得します。
+	fn lock_credentials(&self) -> Result<Option<CredentialLock>, Box<dyn std::error::Error>> {
+		return match &self.credential_store {
+			Some(store) => store.lock(),
+			None => Ok(None),
+		};
+	}
+
+	/// 他のプロセスやスレッドが先に更新した資格情報があれば、読み込み直します。
+	///
+	/// 同じクライアントの同じ更新トークンから発行されたものだけを使い、
+	/// 別のアカウントや authorized_user の資格情報を、共有された保存先の内容で置き換えないようにします。
+	/// 読み込んだアクセストークンがまだ有効であれば true を返します。
+	fn reload_refreshed_credentials(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+		let stored = match &self.credential_store {
+			Some(store) => store.load()?,
+			None => None,
+		};
+		let stored = match stored {
+			Some(stored) if stored.refresh_token.is_some() => stored,
+			_ => return Ok(false),
+		};
+		if stored.client_id.as_deref() != Some(self.client_id.as_str())
+			|| stored.refresh_token != self.token_data.refresh_token
+			|| stored.access_token == self.token_data.access_token
+		{
+			return Ok(false);
+		}
+		info!("他のプロセスが更新した資格情報を読み込みました。");
+		self.token_data = stored;
+		return Ok(!self.access_token_expires_soon());
+	}
+
+	/// 現在の資格情報を破棄し、保存先からも削除します。
+	fn evict_credentials(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		self.token_data.clear();
+		if let Some(store) = &self.credential_store {
+			store.delete()?;
+		}
+		return Ok(());
+	}
+
+	/// 認可手続きの方法を設定します。
+	pub fn set_login_method(&mut self, login_method: LoginMethod) {
+		self.login_method = login_method;
+	}
+
+	/// 更新トークンが無効になった場合 (invalid_grant) に、認可手続きをやり直すかどうかを設定します。
+	///
+	/// 既定は false で、呼び出し元に `OAuth2Error::TokenEndpoint` を返します。
+	pub fn set_auto_relogin(&mut self, auto_relogin: bool) {
+		self.auto_relogin = auto_relogin;
+	}
+
+	/// 更新トークンの有効期間を設定します。
+	///
+	/// 公開ステータスが「テスト」のアプリケーションでは、更新トークンは 7 日で失効します。
+	/// 設定すると、有効期限が近づいたときに警告を出力します。
+	pub fn set_refresh_token_lifetime(&mut self, lifetime: Option<chrono::Duration>) {
+		self.refresh_token_lifetime = lifetime;
+		self.check_refresh_token_lifetime();
+	}
+
+	/// 更新トークンの有効期限を返します。有効期間や受け取った日時が分からない場合は None を返します。
+	pub fn refresh_token_expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+		self.token_data.refresh_token.as_ref()?;
+		let issued_at = self.token_data.refresh_token_issued_at?;
+		return Some(issued_at + self.refresh_token_lifetime?);
+	}
+
+	/// 更新トークンの有効期限が近ければ警告します。
+	fn check_refresh_token_lifetime(&self) {
+		let expires_at = match self.refresh_token_expires_at() {
+			Some(expires_at) => expires_at,
+			None => return,
+		};
+		let now = chrono::Utc::now();
+		let local = expires_at
+			.with_timezone(&chrono::Local)
+			.format("%Y-%m-%d %H:%M:%S");
+		if expires_at <= now {
+			warn!(
+				"更新トークンは有効期限 ({}) を過ぎています。認可手続きが必要です。",
+				local
+			);
+		} else if expires_at <= now + chrono::Duration::hours(REFRESH_TOKEN_EXPIRY_WARNING_HOURS) {
+			warn!("更新トークンはまもなく有効期限 ({}) を迎えます。", local);
+		}
+	}
+
+	/// 許可する Google Workspace のドメインを設定します。
+	///
+	/// 認可リクエストに hd を付け (1 つであればそのドメイン、複数であれば "*")、
+	/// 受け取った ID トークンの署名を確認したうえで、hd クレームと email_verified を検証します。
+	/// hd は画面上のヒントにすぎないため、制限はこの検証によって行われます。
+	pub fn set_allowed_domains(&mut self, domains: Vec<String>) {
+		self.allowed_domains = domains.iter().map(|d| d.trim().to_lowercase()).collect();
+	}
+
+	/// ID トークンを検証し、クレームを返します。
+	///
+	/// 公開鍵がみつからない場合は、鍵の更新に備えて JWKS を取得し直します。
+	pub fn verify_id_token(&mut self, id_token: &str) -> Result<IdTokenClaims, Box<dyn std::error::Error>> {
+		let kid = id_token::decode_unverified(id_token)?
+			.header
+			.kid
+			.unwrap_or_default();
+		let has_key = self.jwks.as_ref().is_some_and(|jwks| jwks.find(&kid).is_some());
+		if !has_key {
+			debug!("公開鍵を取得しています...");
+			let jwks = id_token::fetch_jwks(
+				self.transport.as_ref(),
+				&self.retry_policy,
+				&self.wellknown_endpoints.jwks_uri,
+			)?;
+			self.jwks = Some(jwks);
+		}
+		let jwks = self.jwks.as_ref().ok_or("公開鍵がありません。")?;
+		let claims = id_token::verify(
+			id_token,
+			jwks,
+			Some(&self.client_id),
+			chrono::Utc::now(),
+			self.clock_skew,
+		)?;
+		self.verified_id_token = Some(id_token.to_string());
+		return Ok(claims);
+	}
+
+	/// 受け取った ID トークンを検証します。
+	///
+	/// nonce が認可リクエストのものと一致し、未使用であること、
+	/// アカウントが許可されたドメインのものであることを確認します。
+	fn check_received_id_token(
+		&mut self,
+		token_info: &TokenData,
+		nonce: Option<&str>,
+	) -> Result<(), Box<dyn std::error::Error>> {
+		if self.allowed_domains.is_empty() && nonce.is_none() {
+			return Ok(());
+		}
+		let id_token = token_info.id_token.as_deref().ok_or_else(|| {
+			OAuth2Error::InvalidIdToken("ID トークンがありません。openid スコープが必要です。".to_string())
+		})?;
+		let claims = self.verify_id_token(id_token)?;
+
+		if let Some(nonce) = nonce {
+			if claims.nonce.as_deref() != Some(nonce) {
+				return Err(Box::new(OAuth2Error::NonceMismatch));
+			}
+			if !self.consumed_nonces.consume(nonce) {
+				return Err(Box::new(OAuth2Error::NonceReplayed));
+			}
+		}
+		if self.allowed_domains.is_empty() {
+			return Ok(());
+		}
+
+		return self.check_allowed_account(claims.hd, claims.email, claims.email_verified);
+	}
+
+	/// 保存された資格情報や既存の更新トークンのアカウントが、許可されたドメインのものであることを確認します。
+	///
+	/// 認可手続きを経ないため ID トークンの代わりに userinfo を問い合わせ、hd と email_verified を確認します。
+	pub fn verify_allowed_domain(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		if self.allowed_domains.is_empty() {
+			return Ok(());
+		}
+		let user_profile = self.query_user_info()?;
+		return self.check_allowed_account(user_profile.hd, user_profile.email, user_profile.email_verified);
+	}
+
+	/// アカウントのメールアドレスが確認済みで、許可されたドメインのものであることを確認します。
+	fn check_allowed_account(
+		&self,
+		hd: Option<String>,
+		email: Option<String>,
+		email_verified: Option<bool>,
+	) -> Result<(), Box<dyn std::error::Error>> {
+		if email_verified != Some(true) {
+			return Err(Box::new(OAuth2Error::EmailNotVerified(email)));
+		}
+		let allowed = hd
+			.as_deref()
+			.is_some_and(|hd| self.allowed_domains.contains(&hd.to_lowercase()));
+		if !allowed {
+			return Err(Box::new(OAuth2Error::DomainNotAllowed { hd, email }));
+		}
+		return Ok(());
+	}
+
+	/// アクセストークンの検証方針を設定します。
+	pub fn set_verification_policy(&mut self, verification_policy: VerificationPolicy) {
+		self.verification_policy = verification_policy;
+	}
+
+	/// tokeninfo エンドポイントを設定します。
+	pub fn set_tokeninfo_endpoint(&mut self, endpoint: &str) {
+		self.tokeninfo_endpoint = endpoint.to_string();
+	}
+
+	/// ブラウザーによる認可で使用するリダイレクト URI を設定します。
+	///
+	/// ウェブ アプリケーションの client secret では、登録済みの `http://localhost:PORT` を指定し、そのポートで待ち受けます。
+	pub fn set_redirect_uri(&mut self, redirect_uri: &str) -> Result<(), Box<dyn std::error::Error>> {
+		if util::loopback_port(redirect_uri).is_none() {
+			return Err(format!(
+				"リダイレクト URI は http://localhost:PORT 形式で指定してください。[{}]",
+				redirect_uri
+			)
+			.into());
+		}
+		self.redirect_uri = Some(redirect_uri.to_string());
+		return Ok(());
+	}
+
+	/// トークンのライフサイクルのイベント (認可、更新、無効化、invalid_grant) を受け取るものを追加します。
+	pub fn add_observer(&mut self, observer: Box<dyn TokenEventObserver>) {
+		self.observers.push(observer);
+	}
+
+	/// 現在の資格情報についてのイベントを通知します。
+	fn notify(&self, kind: TokenEventKind) {
+		if self.observers.is_empty() {
+			return;
+		}
+		let id_token_verified = self
+			.token_data
+			.id_token()
+			.is_some_and(|id_token| self.verified_id_token.as_deref() == Some(id_token));
+		let event = TokenEvent::new(kind, &self.client_id, &self.token_data, id_token_verified);
+		for observer in &self.observers {
+			observer.on_event(&event);
+		}
+	}
+
+	/// 再試行の方針を設定します。
+	///
+	/// コンストラクターで行う設定の取得には、既定の方針が使用されます。
+	pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+		self.retry_policy = retry_policy;
+	}
+
+	/// 冪等なリクエストを、再試行の方針に従って送信します。
+	fn send_idempotent(&self, request: &HttpRequest) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		return self.retry_policy.send(self.transport.as_ref(), request);
+	}
+
 	/// 認可手続きを行います。
 	///
 	/// 成功した場合は、アクセストークンを返します。
 	pub fn begin(&mut self) -> Result<(), Box<dyn std::error::Error>> {
 		info!("認可手続きを開始しています...");
+		self.authorization_options.validate()?;
+		self.authorization_options
+			.validate_allowed_domains(&self.allowed_domains)?;
 
-		// ランダムなポートを選択します。
-		let port = util::select_random_tcp_port()?;
 		// リダイレクトURI(必須)
-		let redirect_uri = format!("http://localhost:{}", port);
+		// 登録済みの URI が設定されていればそのポートを、無ければランダムなポートを選択します。
+		let (port, redirect_uri) = match &self.redirect_uri {
+			Some(redirect_uri) => (util::loopback_port(redirect_uri).unwrap(), redirect_uri.clone()),
+			None => {
+				let port = util::select_random_tcp_port()?;
+				(port, format!("http://localhost:{}", port))
+			}
+		};
 		// 状態識別用(推奨)
 		let state = util::generate_random_string(32);
 		// コード検証ツール(推奨)
 		let code_verifier = util::generate_random_string(32);
 		// コードチャレンジ(推奨)
 		let code_challenge = util::generate_code_challenge(&code_verifier);
+		// ID トークンとリクエストの結び付け用 (openid スコープの場合)
+		let nonce = self.next_nonce();
 
 		// ========== ブラウザーで認可画面を開く ==========
 		// Google OAuth による認可手続きの開始を要求します。
-		self.open_browser_to_begin(&redirect_uri, &state, &code_challenge)?;
+		self.open_browser_to_begin(&redirect_uri, &state, &code_challenge, nonce.as_deref())?;
 
 		// ========== HTTP サーバーを立ち上げてリダイレクトを待つ ==========
 		// 応答を受け取るための HTTP サーバーを立ち上げます。
-		let (code, state) = recv_response(port)?;
+		let (code, returned_state) = recv_response(port)?;
+		if returned_state != state {
+			return Err(Box::new(OAuth2Error::StateMismatch));
+		}
 
 		// ========== トークンに変換 >> Google API ==========
 		// アクセストークンをリクエスト
 		let token_info = self.exchange_code_to_tokens(&state, &code, &code_verifier, &redirect_uri)?;
+		debug!(
+			"GOOGLE> token_info: {}",
+			serde_json::to_string_pretty(&token_info)?
+		);
+		self.check_received_id_token(&token_info, nonce.as_deref())?;
+
+		// 再同意の場合は refresh_token が含まれないため、現在の値を引き継ぎます。
+		self.token_data.merge(token_info);
+		if self.token_data.refresh_token.is_none() {
+			warn!("更新トークンを受け取れませんでした。必要な場合は access_type=offline, prompt=consent を指定してください。");
+		}
+		let _lock = self.lock_credentials()?;
+		self.save_credentials()?;
+		self.notify(TokenEventKind::Authorized);
+
+		return Ok(());
+	}
+
+	/// 認可リクエストの nonce を返します。openid スコープが無ければ None を返します。
+	///
+	/// 指定された nonce は使用済みとして記録されるため、最初の認可リクエストだけで使い、
+	/// 再認可などの次の認可リクエストでは新しく生成します。
+	fn next_nonce(&mut self) -> Option<String> {
+		if !self.authorization_options.has_openid_scope() {
+			return None;
+		}
+		let nonce = self.authorization_options.nonce.take();
+		return Some(nonce.unwrap_or_else(|| util::generate_random_string(32)));
+	}
+
+	/// 設定された方法で認可手続きを行います。
+	///
+	/// `LoginMethod::Auto` の場合、ブラウザーを開けない環境 (SSH 接続など) ではデバイスフローを使用します。
+	pub fn login(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		let use_device_flow = match self.login_method {
+			LoginMethod::Auto => util::is_headless(),
+			LoginMethod::Browser => false,
+			LoginMethod::Device => true,
+		};
+		if use_device_flow {
+			return self.begin_device_flow();
+		}
+		return self.begin();
+	}
+
+	/// デバイスフローで認可手続きを行います。
+	///
+	/// 表示された URL を別の端末のブラウザーで開き、コードを入力します。
+	/// クライアントの種類が「テレビと入力が限られたデバイス」である必要があります。
+	///
+	/// # References
+	/// - [テレビと入力が限られたデバイス向けの OAuth 2.0](https://developers.google.com/identity/protocols/oauth2/limited-input-device?hl=ja)
+	pub fn begin_device_flow(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		info!("デバイスフローで認可手続きを開始しています...");
+		self.authorization_options.validate()?;
+		self.authorization_options
+			.validate_allowed_domains(&self.allowed_domains)?;
+
+		let endpoint = self
+			.wellknown_endpoints
+			.device_authorization_endpoint
+			.clone()
+			.ok_or("デバイス認可エンドポイントがありません。")?;
+		let scope = self.authorization_options.scope();
+		let params = [("client_id", self.client_id.as_str()), ("scope", scope.as_str())];
+		let response = self.transport.send(&HttpRequest::post_form(&endpoint, &params))?;
+		let value = parse_token_response(response)?;
+		let device: DeviceAuthorizationResponse = serde_json::from_value(value)?;
+
 		info!(
+			"{} を開き、コード {} を入力してください。",
+			device.verification_url, device.user_code
+		);
+
+		let token_info = self.poll_device_token(&device)?;
+		debug!(
 			"GOOGLE> token_info: {}",
 			serde_json::to_string_pretty(&token_info)?
 		);
+		// デバイスフローは nonce に対応していません。
+		self.check_received_id_token(&token_info, None)?;
 
-		self.token_data = token_info;
+		self.token_data.merge(token_info);
+		let _lock = self.lock_credentials()?;
+		self.save_credentials()?;
+		self.notify(TokenEventKind::Authorized);
 
 		return Ok(());
 	}
 
+	/// ユーザーが認可するまで、トークンエンドポイントに問い合わせます。
+	fn poll_device_token(
+		&self,
+		device: &DeviceAuthorizationResponse,
+	) -> Result<TokenData, Box<dyn std::error::Error>> {
+		let deadline = std::time::Instant::now() + std::time::Duration::from_secs(device.expires_in);
+		let mut interval = std::time::Duration::from_secs(
+			device.interval.unwrap_or(DEFAULT_DEVICE_POLLING_INTERVAL_SECONDS),
+		);
+		let params = [
+			("client_id", self.client_id.as_str()),
+			("client_secret", self.client_secret.as_str()),
+			("device_code", device.device_code.as_str()),
+			("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+		];
+		let request = HttpRequest::post_form(&self.wellknown_endpoints.token_endpoint, &params);
+
+		loop {
+			if deadline <= std::time::Instant::now() {
+				return Err("認可手続きの待機時間が device_code の有効期間を超えたため、手続きはタイムアウトしました。".into());
+			}
+			std::thread::sleep(interval);
+
+			let response = self.send_idempotent(&request)?;
+			let e = match parse_token_response(response) {
+				Ok(value) => {
+					let mut token_info: TokenData = serde_json::from_value(value)?;
+					token_info.stamp(chrono::Utc::now());
+					return Ok(token_info);
+				}
+				Err(e) => e,
+			};
+			match as_oauth2_error(e.as_ref()).and_then(|e| e.token_error()) {
+				Some("authorization_pending") => continue,
+				Some("slow_down") => interval += std::time::Duration::from_secs(5),
+				_ => return Err(e),
+			}
+		}
+	}
+
 	/// code などを使って、アクセストークンを取得します。
 	fn exchange_code_to_tokens(
 		&self,
@@ -264,39 +1191,204 @@ impl GoogleOAuth2 {
 		code_verifier: &str,
 		redirect_uri: &str,
 	) -> Result<TokenData, Box<dyn std::error::Error>> {
-		let mut params = std::collections::HashMap::new();
-		params.insert("code", code);
-		params.insert("client_id", &self.client_id);
-		params.insert("state", &state);
-		params.insert("scope", "");
-		params.insert("client_secret", &self.client_secret);
-		params.insert("redirect_uri", redirect_uri);
-		params.insert("grant_type", "authorization_code");
-		params.insert("code_verifier", &code_verifier);
+		let params = [
+			("code", code),
+			("client_id", self.client_id.as_str()),
+			("state", state),
+			("scope", ""),
+			("client_secret", self.client_secret.as_str()),
+			("redirect_uri", redirect_uri),
+			("grant_type", "authorization_code"),
+			("code_verifier", code_verifier),
+		];
 
-		let text = util::http_post(&self.wellknown_endpoints.token_endpoint, &params)?;
+		// 認可コードは一度しか使えないため、再試行しません。
+		let request = HttpRequest::post_form(&self.wellknown_endpoints.token_endpoint, &params);
+		let response = self.transport.send(&request)?;
+		let value = parse_token_response(response)?;
 
-		let token_info: TokenData = serde_json::from_str(&text)?;
+		let mut token_info: TokenData = serde_json::from_value(value)?;
+		token_info.stamp(chrono::Utc::now());
 
 		return Ok(token_info);
 	}
 
+	/// 更新トークンを設定します。
+	///
+	/// authorized_user 形式の資格情報など、既存の更新トークンを使う場合に使用します。
+	pub fn set_refresh_token(&mut self, refresh_token: &str) {
+		self.token_data.refresh_token = Some(refresh_token.to_string());
+	}
+
+	/// 更新トークンを使って、アクセストークンを再取得します。
+	///
+	/// 保存先が他のプロセスと共有するファイルであれば、読み込みから書き込みまでロックし、
+	/// 他のプロセスやスレッドが先に更新していれば、その資格情報を使います。
+	pub fn refresh_access_token(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		let _lock = self.lock_credentials()?;
+		if self.reload_refreshed_credentials()? {
+			return Ok(());
+		}
+
+		info!("アクセストークンを更新しています...");
+
+		let refresh_token = self
+			.token_data
+			.refresh_token
+			.clone()
+			.ok_or("更新トークンがありません。")?;
+
+		let params = [
+			("client_id", self.client_id.as_str()),
+			("client_secret", self.client_secret.as_str()),
+			("refresh_token", refresh_token.as_str()),
+			("grant_type", "refresh_token"),
+		];
+
+		let request = HttpRequest::post_form(&self.wellknown_endpoints.token_endpoint, &params);
+		let response = self.send_idempotent(&request)?;
+		let value = match parse_token_response(response) {
+			Ok(value) => value,
+			Err(e) => {
+				// 更新トークンが失効、取り消し、ローテーション済みの場合は、保存された資格情報も使えません。
+				if as_oauth2_error(e.as_ref()).is_some_and(|e| e.is_invalid_grant()) {
+					warn!("更新トークンが無効になったため、資格情報を破棄します。");
+					self.notify(TokenEventKind::InvalidGrant);
+					self.evict_credentials()?;
+				}
+				return Err(e);
+			}
+		};
+
+		let mut token_info: TokenData = serde_json::from_value(value)?;
+		token_info.stamp(chrono::Utc::now());
+
+		// 更新の応答には通常 refresh_token が含まれないため、現在の値を引き継ぎます。
+		self.token_data.merge(token_info);
+		self.save_credentials()?;
+		self.notify(TokenEventKind::Refreshed);
+		self.check_refresh_token_lifetime();
+
+		return Ok(());
+	}
+
+	/// アクセストークンを更新します。
+	///
+	/// 更新トークンが無効になっていて、再認可が有効な場合は、認可手続きをやり直します。
+	fn refresh_or_relogin(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		let e = match self.refresh_access_token() {
+			Ok(()) => return Ok(()),
+			Err(e) => e,
+		};
+		if !self.auto_relogin || !as_oauth2_error(e.as_ref()).is_some_and(|e| e.is_invalid_grant()) {
+			return Err(e);
+		}
+		info!("認可手続きをやり直します。");
+		return self.login();
+	}
+
+	/// アクセストークンが無いか、まもなく有効期限が切れるかどうかを返します。
+	fn access_token_expires_soon(&self) -> bool {
+		return self.token_data.expires_within(self.clock_skew);
+	}
+
+	/// クロックのずれに対する余裕を設定します。有効期限のこの時間前に、アクセストークンを更新します。
+	pub fn set_clock_skew(&mut self, clock_skew: chrono::Duration) {
+		self.clock_skew = clock_skew;
+	}
+
+	/// 現在のトークンを返します。
+	pub fn token_data(&self) -> &TokenData {
+		return &self.token_data;
+	}
+
+	/// 有効なアクセストークンを返します。
+	///
+	/// アクセストークンが無いか、まもなく有効期限が切れる場合は、更新トークンを使って更新します。
+	pub fn ensure_access_token(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+		if self.access_token_expires_soon() && self.token_data.refresh_token.is_some() {
+			self.refresh_or_relogin()?;
+		}
+		if self.token_data.access_token.is_empty() {
+			return Err("アクセストークンがありません。".into());
+		}
+		return Ok(self.token_data.access_token.clone());
+	}
+
+	/// リクエストを送信します。GET と HEAD は、再試行の方針に従って再試行します。
+	pub fn send_request(&self, request: &HttpRequest) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		if request.method == "GET" || request.method == "HEAD" {
+			return self.send_idempotent(request);
+		}
+		return self.transport.send(request);
+	}
+
+	/// アクセストークンを付けてリクエストを送信します。
+	///
+	/// 送信の前に必要であればアクセストークンを更新し、401 が返された場合は、
+	/// アクセストークンを強制的に更新して一度だけ再試行します。
+	pub fn send_authorized(
+		&mut self,
+		request: &HttpRequest,
+	) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		let access_token = self.ensure_access_token()?;
+		let response = self.send_request(&request.clone().with_bearer(&access_token))?;
+		if response.status != 401 || self.token_data.refresh_token.is_none() {
+			return Ok(response);
+		}
+
+		info!("アクセストークンが拒否されたため、更新して再試行します。");
+		self.refresh_or_relogin()?;
+		let access_token = self.token_data.access_token.clone();
+		return self.send_request(&request.clone().with_bearer(&access_token));
+	}
+
+	/// 現在の資格情報を gcloud の authorized_user 形式で返します。
+	pub fn to_authorized_user(&self) -> Result<configuration::AuthorizedUser, Box<dyn std::error::Error>> {
+		let refresh_token = self
+			.token_data
+			.refresh_token
+			.clone()
+			.ok_or("更新トークンがありません。")?;
+		let user = configuration::AuthorizedUser {
+			client_id: self.client_id.clone(),
+			client_secret: self.client_secret.clone(),
+			refresh_token,
+			quota_project_id: None,
+			credential_type: "authorized_user".to_string(),
+		};
+		return Ok(user);
+	}
+
 	/// Google OAuth による認可手続き要求します。
 	fn open_browser_to_begin(
 		&self,
 		redirect_uri: &str,
 		state: &str,
 		code_challenge: &str,
+		nonce: Option<&str>,
 	) -> Result<(), Box<dyn std::error::Error>> {
-		let url = format!(
+		let mut url = format!(
             "{authorization_endpoint}?response_type=code&scope={scopes}&redirect_uri={redirect_uri}&client_id={client_id}&state={state}&code_challenge={code_challenge}&code_challenge_method=S256",
 			authorization_endpoint = &self.wellknown_endpoints.authorization_endpoint,
-            scopes = util::urlencode("openid profile email"),
-            redirect_uri = util::urlencode(&redirect_uri),
+            scopes = util::urlencode(&self.authorization_options.scope()),
+            redirect_uri = util::urlencode(redirect_uri),
             client_id = &self.client_id,
-            state = util::urlencode(&state),
+            state = util::urlencode(state),
             code_challenge = code_challenge
 		);
+		let mut options = self.authorization_options.clone();
+		if options.hd.is_none() && !self.allowed_domains.is_empty() {
+			// 複数のドメインを許可する場合は、Workspace のアカウントだけを表示させます。
+			options.hd = match self.allowed_domains.as_slice() {
+				[domain] => Some(domain.clone()),
+				_ => Some("*".to_string()),
+			};
+		}
+		options.nonce = nonce.map(|n| n.to_string());
+		for (key, value) in options.to_query_parameters() {
+			url.push_str(&format!("&{}={}", key, util::urlencode(&value)));
+		}
 
 		util::open_browser(&url)?;
 
@@ -304,36 +1396,344 @@ impl GoogleOAuth2 {
 	}
 
 	/// トークンの有効性を確認します。
+	///
+	/// 検証方針に従って、aud, azp, スコープ、残りの有効期間、メールアドレスの確認を検証します。
 	pub fn verify_access_token(&self) -> Result<TokenVerificationResult, Box<dyn std::error::Error>> {
-		let access_token = &self.token_data.access_token;
+		let token_info = query_tokeninfo(
+			self.transport.as_ref(),
+			&self.retry_policy,
+			&self.tokeninfo_endpoint,
+			TokenKind::AccessToken,
+			&self.token_data.access_token,
+		)?;
+		self.verification_policy.check(&token_info, &self.client_id)?;
 
-		// TODO: この URL は wellknown に無いため、公開されていない手続きなのかもしれない。
-		let uri = format!(
-			"https://oauth2.googleapis.com/tokeninfo?access_token={}",
-			access_token
-		);
-		let text = util::http_get(&uri)?;
+		return Ok(token_info);
+	}
 
-		let token_info: TokenVerificationResult = serde_json::from_str(&text)?;
+	/// ID トークンを tokeninfo エンドポイントで確認します。
+	///
+	/// 署名と有効期限は Google が確認し、aud がクライアント ID と一致することをここで確認します。
+	pub fn verify_id_token_remotely(
+		&self,
+		id_token: &str,
+	) -> Result<TokenVerificationResult, Box<dyn std::error::Error>> {
+		let token_info = query_tokeninfo(
+			self.transport.as_ref(),
+			&self.retry_policy,
+			&self.tokeninfo_endpoint,
+			TokenKind::IdToken,
+			id_token,
+		)?;
+		let aud = token_info.aud().unwrap_or_default();
+		if aud != self.client_id {
+			return Err(Box::new(OAuth2Error::AudienceMismatch {
+				expected: self.client_id.clone(),
+				actual: aud.to_string(),
+			}));
+		}
 
 		return Ok(token_info);
 	}
 
 	/// ユーザープロファイルを問い合わせます。
-	pub fn query_user_info(&self) -> Result<UserProfile, Box<dyn std::error::Error>> {
-		let access_token = &self.token_data.access_token;
+	pub fn query_user_info(&mut self) -> Result<UserProfile, Box<dyn std::error::Error>> {
 		let url = self.wellknown_endpoints.userinfo_endpoint.as_str();
 
-		let mut headers = reqwest::header::HeaderMap::new();
-		let value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", access_token))?;
-		headers.insert("Authorization", value);
-
-		let client = reqwest::blocking::Client::new();
-		let response = client.get(url).headers(headers).send()?;
-		let text = response.text()?;
+		let request = HttpRequest::get(url);
+		let response = self.send_authorized(&request)?;
+		let text = get_response_text(response)?;
 
 		let user_profile: UserProfile = serde_json::from_str(&text)?;
 
 		return Ok(user_profile);
 	}
+
+	/// トークンを無効にします。
+	///
+	/// 更新トークンがあれば更新トークンを、無ければアクセストークンを無効にします。
+	/// 更新トークンを無効にすると、そこから発行されたアクセストークンも無効になります。
+	pub fn revoke(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+		let token = match &self.token_data.refresh_token {
+			Some(refresh_token) => refresh_token.clone(),
+			None => self.token_data.access_token.clone(),
+		};
+		if token.is_empty() {
+			return Err("無効にするトークンがありません。".into());
+		}
+
+		info!("トークンを無効にしています...");
+		let request = HttpRequest::post_form(
+			&self.wellknown_endpoints.revocation_endpoint,
+			&[("token", &token)],
+		);
+		let response = self.transport.send(&request)?;
+		get_response_text(response)?;
+
+		self.notify(TokenEventKind::Revoked);
+		self.evict_credentials()?;
+
+		return Ok(());
+	}
+}
+
+impl TokenProvider for GoogleOAuth2 {
+	fn get_access_token(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+		return self.ensure_access_token();
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::transport::MockTransport;
+
+	/// テスト用の discovery ドキュメント
+	const DISCOVERY: &str = r#"{
+		"issuer": "https://accounts.google.com",
+		"authorization_endpoint": "https://accounts.google.com/o/oauth2/v2/auth",
+		"device_authorization_endpoint": "https://oauth2.googleapis.com/device/code",
+		"token_endpoint": "https://oauth2.googleapis.com/token",
+		"userinfo_endpoint": "https://openidconnect.googleapis.com/v1/userinfo",
+		"revocation_endpoint": "https://oauth2.googleapis.com/revoke",
+		"jwks_uri": "https://www.googleapis.com/oauth2/v3/certs",
+		"response_types_supported": ["code"],
+		"subject_types_supported": ["public"],
+		"id_token_signing_alg_values_supported": ["RS256"],
+		"scopes_supported": ["openid", "email", "profile"],
+		"token_endpoint_auth_methods_supported": ["client_secret_post"],
+		"claims_supported": ["sub", "email"],
+		"code_challenge_methods_supported": ["S256"]
+	}"#;
+
+	/// discovery を済ませた、MockTransport で通信するインスタンスを返します。
+	pub(crate) fn mock_service() -> (GoogleOAuth2, std::sync::Arc<MockTransport>) {
+		let transport = std::sync::Arc::new(MockTransport::new());
+		transport.push_response(200, DISCOVERY);
+		let service =
+			GoogleOAuth2::with_transport("client-id", "client-secret", Box::new(transport.clone())).unwrap();
+		return (service, transport);
+	}
+
+	/// リクエストのフォームの値を返します。
+	fn form_value(request: &HttpRequest, key: &str) -> Option<String> {
+		let body = String::from_utf8(request.body.clone()).unwrap();
+		let params = util::QueryParameters::parse(&body).unwrap();
+		return params.get(key).map(|v| v.to_string());
+	}
+
+	#[test]
+	fn discovery_sets_the_endpoints() {
+		let (service, transport) = mock_service();
+
+		let requests = transport.requests();
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].method, "GET");
+		assert_eq!(requests[0].url, get_wellknown_schema_url());
+		assert_eq!(
+			service.wellknown_endpoints.token_endpoint,
+			"https://oauth2.googleapis.com/token"
+		);
+	}
+
+	#[test]
+	fn exchanges_the_authorization_code_for_tokens() {
+		let (service, transport) = mock_service();
+		transport.push_response(
+			200,
+			r#"{"access_token":"at","expires_in":3599,"refresh_token":"rt","scope":"openid email","token_type":"Bearer"}"#,
+		);
+
+		let token_data = service
+			.exchange_code_to_tokens("state", "code", "verifier", "http://127.0.0.1:8080")
+			.unwrap();
+
+		assert_eq!(token_data.access_token, "at");
+		assert_eq!(token_data.refresh_token(), Some("rt"));
+		assert_eq!(token_data.scopes(), vec!["openid", "email"]);
+		assert!(token_data.expires_at().is_some());
+		let request = &transport.requests()[1];
+		assert_eq!(request.url, "https://oauth2.googleapis.com/token");
+		assert_eq!(
+			form_value(request, "grant_type").as_deref(),
+			Some("authorization_code")
+		);
+		assert_eq!(form_value(request, "code").as_deref(), Some("code"));
+		assert_eq!(form_value(request, "code_verifier").as_deref(), Some("verifier"));
+	}
+
+	#[test]
+	fn refresh_keeps_the_previous_refresh_token() {
+		let (mut service, transport) = mock_service();
+		service.set_refresh_token("rt");
+		transport.push_response(
+			200,
+			r#"{"access_token":"at2","expires_in":3599,"token_type":"Bearer"}"#,
+		);
+
+		service.refresh_access_token().unwrap();
+
+		assert_eq!(service.token_data().access_token, "at2");
+		assert_eq!(service.token_data().refresh_token(), Some("rt"));
+		let request = &transport.requests()[1];
+		assert_eq!(
+			form_value(request, "grant_type").as_deref(),
+			Some("refresh_token")
+		);
+		assert_eq!(form_value(request, "refresh_token").as_deref(), Some("rt"));
+	}
+
+	/// 他のプロセスが同じファイルに資格情報を保存したものとして書き込みます。
+	fn stored_by_another_process(path: &std::path::Path, json: &str) {
+		let token_data: TokenData = serde_json::from_str(json).unwrap();
+		crate::store::FileStore::new(path).save(&token_data).unwrap();
+	}
+
+	#[test]
+	fn adopts_a_token_refreshed_by_another_process() {
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-reload-{}", std::process::id()));
+		let path = dir.join("token.json");
+		let (mut service, transport) = mock_service();
+		service.set_credential_store(Box::new(crate::store::FileStore::new(&path)));
+		service.set_refresh_token("rt");
+		stored_by_another_process(
+			&path,
+			r#"{"access_token":"at2","refresh_token":"rt","expires_at":"2099-01-01T00:00:00Z","client_id":"client-id"}"#,
+		);
+
+		service.refresh_access_token().unwrap();
+
+		assert_eq!(service.token_data().access_token, "at2");
+		assert_eq!(transport.requests().len(), 1);
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn ignores_a_token_of_another_account_or_client() {
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-foreign-{}", std::process::id()));
+		let path = dir.join("token.json");
+		let (mut service, transport) = mock_service();
+		service.set_credential_store(Box::new(crate::store::FileStore::new(&path)));
+		service.set_refresh_token("rt");
+
+		for stored in [
+			r#"{"access_token":"other","refresh_token":"other-rt","expires_at":"2099-01-01T00:00:00Z","client_id":"client-id"}"#,
+			r#"{"access_token":"other","refresh_token":"rt","expires_at":"2099-01-01T00:00:00Z","client_id":"other-client"}"#,
+		] {
+			stored_by_another_process(&path, stored);
+			transport.push_response(200, r#"{"access_token":"at2","expires_in":3599}"#);
+
+			service.refresh_access_token().unwrap();
+
+			assert_eq!(service.token_data().access_token, "at2");
+			assert_eq!(service.token_data().refresh_token(), Some("rt"));
+			let request = transport.requests().pop().unwrap();
+			assert_eq!(form_value(&request, "refresh_token").as_deref(), Some("rt"));
+		}
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// ID トークンを含むトークンを返します。
+	fn token_with_id_token(claims: &serde_json::Value) -> TokenData {
+		let id_token = crate::id_token::tests::sign(claims);
+		return serde_json::from_value(serde_json::json!({"access_token": "at", "id_token": id_token}))
+			.unwrap();
+	}
+
+	#[test]
+	fn supplied_nonce_is_used_only_for_the_first_request() {
+		let (mut service, _) = mock_service();
+		service.set_authorization_options(authorization::AuthorizationOptions {
+			scopes: vec!["openid".to_string()],
+			nonce: Some("supplied".to_string()),
+			..Default::default()
+		});
+
+		assert_eq!(service.next_nonce().as_deref(), Some("supplied"));
+		let second = service.next_nonce().unwrap();
+		assert_ne!(second, "supplied");
+		assert_ne!(service.next_nonce().unwrap(), second);
+	}
+
+	#[test]
+	fn rejects_a_mismatched_or_replayed_nonce() {
+		let (mut service, _) = mock_service();
+		service.jwks = Some(crate::id_token::tests::test_jwks());
+		let mut claims = crate::id_token::tests::valid_claims();
+		claims["nonce"] = serde_json::json!("n1");
+		let token = token_with_id_token(&claims);
+
+		let e = service.check_received_id_token(&token, Some("n2")).unwrap_err();
+		assert!(matches!(
+			as_oauth2_error(e.as_ref()),
+			Some(OAuth2Error::NonceMismatch)
+		));
+		service.check_received_id_token(&token, Some("n1")).unwrap();
+		let e = service.check_received_id_token(&token, Some("n1")).unwrap_err();
+		assert!(matches!(
+			as_oauth2_error(e.as_ref()),
+			Some(OAuth2Error::NonceReplayed)
+		));
+	}
+
+	#[test]
+	fn invalid_grant_evicts_the_stored_credentials() {
+		let (mut service, transport) = mock_service();
+		service.set_credential_store(Box::new(crate::store::MemoryStore::new()));
+		service.set_refresh_token("revoked");
+		service.save_credentials().unwrap();
+		transport.push_response(
+			400,
+			r#"{"error":"invalid_grant","error_description":"Token has been expired or revoked."}"#,
+		);
+
+		let e = service.refresh_access_token().unwrap_err();
+
+		assert!(as_oauth2_error(e.as_ref()).is_some_and(|e| e.is_invalid_grant()));
+		assert_eq!(service.token_data().refresh_token(), None);
+		assert!(!service.load_credentials().unwrap());
+	}
+
+	#[test]
+	fn an_hd_hint_outside_the_allowed_domains_is_rejected_before_the_browser_opens() {
+		let (mut service, transport) = mock_service();
+		service.set_allowed_domains(vec!["Example.com".to_string()]);
+		service.set_authorization_options(authorization::AuthorizationOptions {
+			hd: Some("other.example".to_string()),
+			..Default::default()
+		});
+
+		for result in [service.begin(), service.begin_device_flow()] {
+			let e = result.unwrap_err();
+			assert!(
+				matches!(
+					as_oauth2_error(e.as_ref()),
+					Some(OAuth2Error::InvalidAuthorizationOptions(_))
+				),
+				"{}",
+				e
+			);
+		}
+		// ディスカバリー以外のリクエストは送信していません。
+		assert_eq!(transport.requests().len(), 1);
+	}
+
+	#[test]
+	fn an_hd_hint_inside_the_allowed_domains_is_accepted() {
+		let allowed = vec!["example.com".to_string(), "example.org".to_string()];
+		let options = |hd: &str| authorization::AuthorizationOptions {
+			hd: Some(hd.to_string()),
+			..Default::default()
+		};
+
+		assert!(options("Example.ORG").validate_allowed_domains(&allowed).is_ok());
+		assert!(options("*").validate_allowed_domains(&allowed).is_ok());
+		assert!(options("other.example").validate_allowed_domains(&[]).is_ok());
+		assert!(options("example.com.evil")
+			.validate_allowed_domains(&allowed)
+			.is_err());
+		assert!(authorization::AuthorizationOptions::default()
+			.validate_allowed_domains(&allowed)
+			.is_ok());
+	}
 }
@@ -0,0 +1,471 @@
+//!
+//! ID トークン (JWT) の検証
+//!
+//! Google の公開鍵 (JWKS) で RS256 の署名を確認し、iss, aud, exp を検証します。
+//!
+//! # References
+//! - [ID トークンの検証](https://developers.google.com/identity/openid-connect/openid-connect?hl=ja#validatinganidtoken)
+
+use crate::error::OAuth2Error;
+use crate::retry::RetryPolicy;
+use crate::transport::{HttpRequest, HttpTransport};
+use crate::util;
+
+/// Google の ID トークンの発行者
+const GOOGLE_ISSUERS: [&str; 2] = ["https://accounts.google.com", "accounts.google.com"];
+
+///
+/// JWT のヘッダー
+///
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone)]
+pub struct IdTokenHeader {
+	/// 署名アルゴリズム
+	pub alg: String,
+	/// 鍵の ID
+	#[serde(default)]
+	pub kid: Option<String>,
+	/// 種類
+	#[serde(default)]
+	pub typ: Option<String>,
+}
+
+///
+/// ID トークンのクレーム
+///
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone)]
+pub struct IdTokenClaims {
+	/// 発行者
+	pub iss: String,
+	/// ユーザー ID
+	pub sub: String,
+	/// 対象のクライアント ID
+	pub aud: String,
+	/// 認可を求めたクライアント ID
+	#[serde(default)]
+	pub azp: Option<String>,
+	/// 有効期限 (UNIX 時刻)
+	pub exp: i64,
+	/// 発行日時 (UNIX 時刻)
+	pub iat: i64,
+	/// メールアドレス
+	#[serde(default)]
+	pub email: Option<String>,
+	/// メールアドレスが確認済みかどうか
+	#[serde(default)]
+	pub email_verified: Option<bool>,
+	/// Google Workspace のドメイン (個人のアカウントには含まれません)
+	#[serde(default)]
+	pub hd: Option<String>,
+	/// 認可リクエストの nonce
+	#[serde(default)]
+	pub nonce: Option<String>,
+	/// その他のクレーム
+	#[serde(flatten)]
+	pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+///
+/// 署名を確認する前の ID トークン
+///
+#[derive(Debug, Clone)]
+pub struct DecodedIdToken {
+	/// ヘッダー
+	pub header: IdTokenHeader,
+	/// クレーム
+	pub claims: IdTokenClaims,
+	/// 署名の対象 ("header.payload")
+	signing_input: String,
+	/// 署名
+	signature: Vec<u8>,
+}
+
+///
+/// JSON Web Key (RSA の公開鍵)
+///
+#[derive(serde_derive::Deserialize, Debug, Clone)]
+pub struct Jwk {
+	/// 鍵の ID
+	pub kid: String,
+	/// 鍵の種類 (RSA)
+	pub kty: String,
+	/// 署名アルゴリズム
+	#[serde(default)]
+	pub alg: Option<String>,
+	/// modulus (BASE64URL)
+	pub n: String,
+	/// exponent (BASE64URL)
+	pub e: String,
+}
+
+///
+/// JSON Web Key Set
+///
+#[derive(serde_derive::Deserialize, Debug, Clone, Default)]
+pub struct Jwks {
+	/// 公開鍵
+	pub keys: Vec<Jwk>,
+}
+
+impl Jwks {
+	///
+	/// kid に一致する公開鍵を返します。
+	///
+	pub fn find(&self, kid: &str) -> Option<&Jwk> {
+		return self.keys.iter().find(|k| k.kid == kid);
+	}
+}
+
+/// 使用済みの nonce を記録する既定の件数
+const DEFAULT_NONCE_STORE_CAPACITY: usize = 64;
+
+///
+/// 使用済みの nonce の記録 (古いものから破棄します)
+///
+#[derive(Debug)]
+pub struct NonceStore {
+	capacity: usize,
+	entries: std::collections::VecDeque<String>,
+}
+
+impl Default for NonceStore {
+	fn default() -> Self {
+		return Self::with_capacity(DEFAULT_NONCE_STORE_CAPACITY);
+	}
+}
+
+impl NonceStore {
+	///
+	/// 記録する件数を指定して、新しいインスタンスを返します。
+	///
+	pub fn with_capacity(capacity: usize) -> Self {
+		return Self {
+			capacity: capacity.max(1),
+			entries: std::collections::VecDeque::new(),
+		};
+	}
+
+	///
+	/// nonce を使用済みにします。既に使用済みであれば false を返します。
+	///
+	pub fn consume(&mut self, nonce: &str) -> bool {
+		if self.entries.iter().any(|n| n == nonce) {
+			return false;
+		}
+		if self.capacity <= self.entries.len() {
+			self.entries.pop_front();
+		}
+		self.entries.push_back(nonce.to_string());
+		return true;
+	}
+}
+
+///
+/// JWKS を取得します。
+///
+/// # Arguments
+/// * `transport` - HTTP 通信を行うもの
+/// * `retry_policy` - 再試行の方針
+/// * `jwks_uri` - JWKS の URL
+///
+pub fn fetch_jwks(
+	transport: &dyn HttpTransport,
+	retry_policy: &RetryPolicy,
+	jwks_uri: &str,
+) -> Result<Jwks, Box<dyn std::error::Error>> {
+	let response = retry_policy.send(transport, &HttpRequest::get(jwks_uri))?;
+	if !response.is_success() {
+		return Err(format!("HTTP {} {}", response.status, response.body).into());
+	}
+	let jwks: Jwks = serde_json::from_str(&response.body)?;
+	return Ok(jwks);
+}
+
+///
+/// ID トークンが不正であることを示すエラーを返します。
+///
+fn invalid(reason: &str) -> Box<dyn std::error::Error> {
+	return Box::new(OAuth2Error::InvalidIdToken(reason.to_string()));
+}
+
+///
+/// ID トークンを、署名を確認せずに分解します。
+///
+pub fn decode_unverified(id_token: &str) -> Result<DecodedIdToken, Box<dyn std::error::Error>> {
+	let parts: Vec<&str> = id_token.split('.').collect();
+	if parts.len() != 3 {
+		return Err(invalid("JWT の形式ではありません。"));
+	}
+	let header = util::decode_base64url(parts[0]).map_err(|_| invalid("ヘッダーを復号できません。"))?;
+	let header: IdTokenHeader =
+		serde_json::from_slice(&header).map_err(|_| invalid("ヘッダーを解析できません。"))?;
+	let payload = util::decode_base64url(parts[1]).map_err(|_| invalid("ペイロードを復号できません。"))?;
+	let claims: IdTokenClaims =
+		serde_json::from_slice(&payload).map_err(|_| invalid("ペイロードを解析できません。"))?;
+	let signature = util::decode_base64url(parts[2]).map_err(|_| invalid("署名を復号できません。"))?;
+	let decoded = DecodedIdToken {
+		header,
+		claims,
+		signing_input: format!("{}.{}", parts[0], parts[1]),
+		signature,
+	};
+	return Ok(decoded);
+}
+
+///
+/// JWT のヘッダーとクレームを、署名を確認せずに JSON として取り出します。
+///
+/// ID トークン以外の JWT の内容を確認するために使用します。
+///
+pub fn decode_jwt(token: &str) -> Result<(serde_json::Value, serde_json::Value), Box<dyn std::error::Error>> {
+	let parts: Vec<&str> = token.trim().split('.').collect();
+	if parts.len() != 3 {
+		return Err("JWT の形式ではありません。".into());
+	}
+	let header: serde_json::Value = serde_json::from_slice(&util::decode_base64url(parts[0])?)?;
+	let claims: serde_json::Value = serde_json::from_slice(&util::decode_base64url(parts[1])?)?;
+	return Ok((header, claims));
+}
+
+///
+/// RS256 の署名を確認します。
+///
+fn verify_signature(
+	jwk: &Jwk,
+	signing_input: &str,
+	signature: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+	use rsa::signature::Verifier;
+
+	if jwk.kty != "RSA" || jwk.alg.as_deref().is_some_and(|alg| alg != "RS256") {
+		return Err(invalid("RS256 以外の鍵には対応していません。"));
+	}
+	let n = rsa::BigUint::from_bytes_be(&util::decode_base64url(&jwk.n)?);
+	let e = rsa::BigUint::from_bytes_be(&util::decode_base64url(&jwk.e)?);
+	let public_key = rsa::RsaPublicKey::new(n, e)?;
+	let verifying_key = rsa::pkcs1v15::VerifyingKey::<rsa::sha2::Sha256>::new(public_key);
+	let signature =
+		rsa::pkcs1v15::Signature::try_from(signature).map_err(|_| invalid("署名が正しくありません。"))?;
+	verifying_key
+		.verify(signing_input.as_bytes(), &signature)
+		.map_err(|_| invalid("署名が正しくありません。"))?;
+	return Ok(());
+}
+
+///
+/// ID トークンを検証し、クレームを返します。
+///
+/// # Arguments
+/// * `id_token` - ID トークン
+/// * `jwks` - Google の公開鍵
+/// * `audience` - 想定する aud (None であれば確認しません)
+/// * `now` - 現在の日時
+/// * `clock_skew` - クロックのずれに対する余裕
+///
+pub fn verify(
+	id_token: &str,
+	jwks: &Jwks,
+	audience: Option<&str>,
+	now: chrono::DateTime<chrono::Utc>,
+	clock_skew: chrono::Duration,
+) -> Result<IdTokenClaims, Box<dyn std::error::Error>> {
+	let DecodedIdToken {
+		header,
+		claims,
+		signing_input,
+		signature,
+	} = decode_unverified(id_token)?;
+
+	if header.alg != "RS256" {
+		return Err(invalid(&format!(
+			"署名アルゴリズム {} には対応していません。",
+			header.alg
+		)));
+	}
+	let kid = header
+		.kid
+		.as_deref()
+		.ok_or_else(|| invalid("kid がありません。"))?;
+	let jwk = jwks
+		.find(kid)
+		.ok_or_else(|| invalid(&format!("公開鍵がみつかりません。[{}]", kid)))?;
+	verify_signature(jwk, &signing_input, &signature)?;
+
+	if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+		return Err(invalid(&format!("発行者が正しくありません。[{}]", claims.iss)));
+	}
+	if audience.is_some_and(|aud| aud != claims.aud) {
+		return Err(invalid(&format!("aud が一致しません。[{}]", claims.aud)));
+	}
+	if claims.exp <= (now - clock_skew).timestamp() {
+		return Err(invalid("有効期限が切れています。"));
+	}
+	if (now + clock_skew).timestamp() < claims.iat {
+		return Err(invalid("発行日時が未来です。"));
+	}
+	return Ok(claims);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	/// テスト用の鍵の ID
+	const TEST_KID: &str = "test-key";
+
+	/// テスト用の RSA の秘密鍵 (生成に時間がかかるため、テスト全体で共有します)
+	fn private_key() -> &'static rsa::RsaPrivateKey {
+		static KEY: std::sync::OnceLock<rsa::RsaPrivateKey> = std::sync::OnceLock::new();
+		return KEY.get_or_init(|| rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap());
+	}
+
+	/// BASE64URL (パディングなし) に変換します。
+	fn encode(buffer: &[u8]) -> String {
+		return util::fix_base64_string(&util::encode_base64(buffer));
+	}
+
+	/// テスト用の鍵の JWKS を返します。
+	pub(crate) fn test_jwks() -> Jwks {
+		use rsa::traits::PublicKeyParts;
+
+		let public_key = private_key().to_public_key();
+		return Jwks {
+			keys: vec![Jwk {
+				kid: TEST_KID.to_string(),
+				kty: "RSA".to_string(),
+				alg: Some("RS256".to_string()),
+				n: encode(&public_key.n().to_bytes_be()),
+				e: encode(&public_key.e().to_bytes_be()),
+			}],
+		};
+	}
+
+	/// クレームにテスト用の鍵で署名した ID トークンを返します。
+	pub(crate) fn sign(claims: &serde_json::Value) -> String {
+		use rsa::signature::{SignatureEncoding, Signer};
+
+		let header = serde_json::json!({"alg": "RS256", "kid": TEST_KID, "typ": "JWT"});
+		let signing_input = format!(
+			"{}.{}",
+			encode(header.to_string().as_bytes()),
+			encode(claims.to_string().as_bytes())
+		);
+		let signing_key = rsa::pkcs1v15::SigningKey::<rsa::sha2::Sha256>::new(private_key().clone());
+		let signature = signing_key.sign(signing_input.as_bytes()).to_bytes();
+		return format!("{}.{}", signing_input, encode(&signature));
+	}
+
+	/// 現在有効な、client-id 向けのクレームを返します。
+	pub(crate) fn valid_claims() -> serde_json::Value {
+		let now = chrono::Utc::now().timestamp();
+		return serde_json::json!({
+			"iss": "https://accounts.google.com",
+			"sub": "1234567890",
+			"aud": "client-id",
+			"exp": now + 3600,
+			"iat": now,
+			"email": "user@example.com",
+			"email_verified": true,
+		});
+	}
+
+	/// ID トークンを検証し、エラーであればその理由を返します。
+	fn verify_error(id_token: &str) -> String {
+		let result = verify(
+			id_token,
+			&test_jwks(),
+			Some("client-id"),
+			chrono::Utc::now(),
+			chrono::Duration::zero(),
+		);
+		return result.unwrap_err().to_string();
+	}
+
+	#[test]
+	fn verifies_a_signed_token() {
+		let id_token = sign(&valid_claims());
+
+		let claims = verify(
+			&id_token,
+			&test_jwks(),
+			Some("client-id"),
+			chrono::Utc::now(),
+			chrono::Duration::zero(),
+		)
+		.unwrap();
+
+		assert_eq!(claims.sub, "1234567890");
+		assert_eq!(claims.email.as_deref(), Some("user@example.com"));
+	}
+
+	#[test]
+	fn rejects_a_tampered_payload() {
+		let id_token = sign(&valid_claims());
+		let mut claims = valid_claims();
+		claims["sub"] = serde_json::json!("someone-else");
+		let forged = sign(&claims);
+		let parts: Vec<&str> = id_token.split('.').collect();
+		let forged_parts: Vec<&str> = forged.split('.').collect();
+
+		let tampered = format!("{}.{}.{}", parts[0], forged_parts[1], parts[2]);
+
+		assert!(verify_error(&tampered).contains("署名が正しくありません"));
+	}
+
+	#[test]
+	fn rejects_an_unknown_key() {
+		let mut jwks = test_jwks();
+		jwks.keys[0].kid = "other-key".to_string();
+
+		let result = verify(
+			&sign(&valid_claims()),
+			&jwks,
+			None,
+			chrono::Utc::now(),
+			chrono::Duration::zero(),
+		);
+
+		assert!(result.unwrap_err().to_string().contains("公開鍵がみつかりません"));
+	}
+
+	#[test]
+	fn rejects_a_wrong_issuer_or_audience() {
+		let mut claims = valid_claims();
+		claims["iss"] = serde_json::json!("https://evil.example.com");
+		assert!(verify_error(&sign(&claims)).contains("発行者が正しくありません"));
+
+		let mut claims = valid_claims();
+		claims["aud"] = serde_json::json!("other-client");
+		assert!(verify_error(&sign(&claims)).contains("aud が一致しません"));
+	}
+
+	#[test]
+	fn rejects_an_expired_token_beyond_the_clock_skew() {
+		let mut claims = valid_claims();
+		let now = chrono::Utc::now();
+		claims["exp"] = serde_json::json!(now.timestamp() - 60);
+		let id_token = sign(&claims);
+
+		assert!(verify_error(&id_token).contains("有効期限が切れています"));
+		let result = verify(
+			&id_token,
+			&test_jwks(),
+			Some("client-id"),
+			now,
+			chrono::Duration::seconds(120),
+		);
+		assert!(result.is_ok());
+	}
+
+	#[test]
+	fn nonce_store_rejects_replays_and_forgets_the_oldest() {
+		let mut store = NonceStore::with_capacity(2);
+
+		assert!(store.consume("a"));
+		assert!(!store.consume("a"));
+		assert!(store.consume("b"));
+		assert!(store.consume("c"));
+		// 上限を超えたため、最も古い "a" は破棄されています。
+		assert!(store.consume("a"));
+		assert!(!store.consume("c"));
+	}
+}
@@ -0,0 +1,28 @@
+//!
+//! Rust + Google OAuth 2.0 のクライアント
+//!
+//! コマンドラインのサンプル (main.rs) のほか、デーモンなどから使用できるように、各モジュールを公開します。
+//!
+//! # References
+//! - [モバイル &デスクトップ アプリ向け OAuth 2.0](https://developers.google.com/identity/protocols/oauth2/native-app?hl=ja)
+
+#![allow(clippy::needless_return, clippy::empty_docs)]
+
+pub mod adc;
+pub mod arguments;
+pub mod audit;
+pub mod authorization;
+pub mod authorized_client;
+pub mod configuration;
+pub mod error;
+pub mod events;
+pub mod gauth2;
+pub mod id_token;
+pub mod lock;
+pub mod logger;
+pub mod refresher;
+pub mod retry;
+pub mod store;
+pub mod transport;
+pub mod util;
+pub mod verification;
@@ -0,0 +1,284 @@
+//!
+//! 資格情報のファイルのロック
+//!
+//! 複数のプロセスが同じファイルの資格情報を同時に更新すると、一方の書き込みが失われ、
+//! 更新トークンのローテーションでは他方の更新トークンが無効になります。
+//! 読み込み、更新、書き込みの間、ファイルの隣に置いたロックファイルをアドバイザリーロックで排他し、
+//! 同じプロセスのスレッドどうしはミューテックスで排他します。
+//! 異常終了したプロセスのロックは OS が解放するため、ロックファイルは取り除きません。
+
+use log::debug;
+
+/// ロックを待つ時間の上限
+const LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(150);
+
+/// ロックを再試行する間隔
+const LOCK_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+///
+/// ロックファイルに書き込む、ロックを保持しているプロセスの情報
+///
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+struct LockOwner {
+	/// プロセス ID
+	pid: u32,
+	/// ロックを取得した日時
+	acquired_at: chrono::DateTime<chrono::Utc>,
+}
+
+///
+/// 同じプロセスの中でロックを保持しているロックファイル
+///
+/// 同じプロセスの中では、ファイルのロックの代わりにこの一覧で排他し、
+/// 待っていたスレッドは先に更新されたトークンを読み込み直して使います。
+///
+struct ProcessLocks {
+	held: std::sync::Mutex<std::collections::HashSet<std::path::PathBuf>>,
+	released: std::sync::Condvar,
+}
+
+///
+/// プロセス内のロックの一覧を返します。
+///
+fn process_locks() -> &'static ProcessLocks {
+	static PROCESS_LOCKS: std::sync::OnceLock<ProcessLocks> = std::sync::OnceLock::new();
+
+	return PROCESS_LOCKS.get_or_init(|| ProcessLocks {
+		held: std::sync::Mutex::new(std::collections::HashSet::new()),
+		released: std::sync::Condvar::new(),
+	});
+}
+
+///
+/// 資格情報のファイルのロック (ドロップすると解放します)
+///
+pub struct CredentialLock {
+	/// ロックファイルのパス
+	lock_path: std::path::PathBuf,
+	/// ロックファイル (閉じるとアドバイザリーロックが解放されます)
+	file: Option<std::fs::File>,
+}
+
+impl CredentialLock {
+	///
+	/// 資格情報のファイルのロックを取得します。
+	///
+	/// 他のプロセスが保持していれば解放されるまで待ちます。
+	///
+	/// # Arguments
+	/// * `path` - 資格情報のファイルのパス (ロックファイルは隣に `.lock` を付けて作ります)
+	///
+	pub fn acquire(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+		let lock_path = lock_path_of(path);
+		let started = std::time::Instant::now();
+
+		// ========== 同じプロセスのスレッドと排他 ==========
+		let locks = process_locks();
+		let mut held = locks.held.lock().unwrap_or_else(|e| e.into_inner());
+		while held.contains(&lock_path) {
+			let remaining = LOCK_TIMEOUT.saturating_sub(started.elapsed());
+			if remaining.is_zero() {
+				return Err(timeout_error(&lock_path));
+			}
+			held = locks
+				.released
+				.wait_timeout(held, remaining)
+				.unwrap_or_else(|e| e.into_inner())
+				.0;
+		}
+		held.insert(lock_path.clone());
+		drop(held);
+		let mut lock = Self {
+			lock_path,
+			file: None,
+		};
+
+		// ========== 他のプロセスと排他 ==========
+		loop {
+			if let Some(file) = try_lock_file(&lock.lock_path)? {
+				debug!("ロックを取得しました。[{}]", lock.lock_path.display());
+				lock.file = Some(file);
+				return Ok(lock);
+			}
+			if LOCK_TIMEOUT <= started.elapsed() {
+				return Err(timeout_error(&lock.lock_path));
+			}
+			std::thread::sleep(LOCK_RETRY_INTERVAL);
+		}
+	}
+}
+
+///
+/// ロックを取得できなかったことを示すエラーを返します。
+///
+/// ロックファイルから保持しているプロセスを読み取れれば、その pid と保持している時間を含めます。
+///
+fn timeout_error(lock_path: &std::path::Path) -> Box<dyn std::error::Error> {
+	let owner = std::fs::read_to_string(lock_path)
+		.ok()
+		.and_then(|text| serde_json::from_str::<LockOwner>(&text).ok());
+	let holder = match owner {
+		Some(owner) => format!(
+			" pid: {}, 保持している時間: {} 秒",
+			owner.pid,
+			(chrono::Utc::now() - owner.acquired_at).num_seconds()
+		),
+		None => String::new(),
+	};
+	return format!(
+		"ロックを取得できません。他のプロセスが資格情報を更新しています。[{}]{}",
+		lock_path.display(),
+		holder
+	)
+	.into();
+}
+
+impl Drop for CredentialLock {
+	fn drop(&mut self) {
+		if let Some(file) = self.file.take() {
+			// 保持していないプロセスの情報が残らないように空にします。
+			let _ = file.set_len(0);
+			let _ = file.unlock();
+		}
+		let locks = process_locks();
+		locks
+			.held
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.remove(&self.lock_path);
+		locks.released.notify_all();
+	}
+}
+
+///
+/// 資格情報のファイルに対応するロックファイルのパスを返します。
+///
+fn lock_path_of(path: &std::path::Path) -> std::path::PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_os_string();
+	name.push(".lock");
+	return path.with_file_name(name);
+}
+
+///
+/// ロックファイルのロックを一度だけ試みます。他のプロセスが保持していれば None を返します。
+///
+fn try_lock_file(lock_path: &std::path::Path) -> Result<Option<std::fs::File>, Box<dyn std::error::Error>> {
+	use std::io::Write;
+
+	// 資格情報のファイルより先にロックファイルを作るため、親ディレクトリが無ければ作成します。
+	if let Some(parent) = lock_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+		std::fs::create_dir_all(parent).map_err(|e| {
+			format!(
+				"ロックファイルのディレクトリを作成できません。[{}] 理由: {}",
+				parent.display(),
+				e
+			)
+		})?;
+	}
+
+	let mut options = std::fs::OpenOptions::new();
+	options.read(true).write(true).create(true).truncate(false);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	let mut file = options.open(lock_path).map_err(|e| {
+		format!(
+			"ロックファイルを開けません。[{}] 理由: {}",
+			lock_path.display(),
+			e
+		)
+	})?;
+
+	match file.try_lock() {
+		Ok(()) => {}
+		Err(std::fs::TryLockError::WouldBlock) => return Ok(None),
+		Err(std::fs::TryLockError::Error(e)) => {
+			return Err(format!("ロックできません。[{}] 理由: {}", lock_path.display(), e).into());
+		}
+	}
+
+	let owner = LockOwner {
+		pid: std::process::id(),
+		acquired_at: chrono::Utc::now(),
+	};
+	file.set_len(0)?;
+	file.write_all(serde_json::to_string(&owner)?.as_bytes())?;
+	file.flush()?;
+	return Ok(Some(file));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn waits_until_the_holder_releases_and_keeps_the_lock_file() {
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-lock-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("token.json");
+
+		let lock = CredentialLock::acquire(&path).unwrap();
+		let owner: LockOwner =
+			serde_json::from_str(&std::fs::read_to_string(lock_path_of(&path)).unwrap()).unwrap();
+		assert_eq!(owner.pid, std::process::id());
+
+		let (sender, receiver) = std::sync::mpsc::channel();
+		let waiter = {
+			let path = path.clone();
+			std::thread::spawn(move || {
+				let _lock = CredentialLock::acquire(&path).unwrap();
+				sender.send(()).unwrap();
+			})
+		};
+		assert!(receiver
+			.recv_timeout(std::time::Duration::from_millis(300))
+			.is_err());
+
+		drop(lock);
+		receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+		waiter.join().unwrap();
+		assert!(lock_path_of(&path).exists());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn waits_while_another_handle_holds_the_file_lock() {
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-flock-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("token.json");
+		let lock_path = lock_path_of(&path);
+
+		// 別に開いたファイルのロックは、他のプロセスのロックと同じく排他されます。
+		let other = std::fs::File::create(&lock_path).unwrap();
+		other.lock().unwrap();
+		assert!(try_lock_file(&lock_path).unwrap().is_none());
+
+		other.unlock().unwrap();
+		let file = try_lock_file(&lock_path).unwrap().unwrap();
+		assert!(matches!(other.try_lock(), Err(std::fs::TryLockError::WouldBlock)));
+		drop(file);
+		other.try_lock().unwrap();
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn creates_the_directory_of_a_new_token_cache() {
+		use crate::store::CredentialStore;
+
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-newdir-{}", std::process::id()));
+		let path = dir.join("nested").join("token.json");
+		let store = crate::store::FileStore::new(&path);
+
+		let lock = store.lock().unwrap();
+		let token_data = serde_json::from_str(r#"{"access_token":"at","refresh_token":"rt"}"#).unwrap();
+		store.save(&token_data).unwrap();
+		drop(lock);
+
+		assert_eq!(store.load().unwrap().unwrap().access_token, "at");
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,276 @@
+//!
+//! ログ出力
+//!
+//! `log` クレートのロガーとして、標準エラー出力にログを書き込みます。
+//! トークンや client secret などの秘密情報は、出力の前に伏せ字にします。
+
+/// ログレベルを指定する環境変数 (trace, debug, info, warn, error, off)
+const LOG_LEVEL_ENV: &str = "R_GOOGLE_OAUTH2_LOG";
+
+/// 伏せ字にするキー
+const SECRET_KEYS: [&str; 8] = [
+	"access_token",
+	"refresh_token",
+	"id_token",
+	"client_secret",
+	"code",
+	"code_verifier",
+	"device_code",
+	"assertion",
+];
+
+/// 伏せ字
+const REDACTED: &str = "***";
+
+///
+/// 標準エラー出力に書き込むロガー
+///
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		// reqwest などの依存クレートのログは、trace のときだけ詳細を出力します。
+		if !metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+			&& log::max_level() < log::LevelFilter::Trace
+		{
+			return metadata.level() <= log::Level::Warn;
+		}
+		return metadata.level() <= log::max_level();
+	}
+
+	fn log(&self, record: &log::Record) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+		let line = redact(&record.args().to_string());
+		let current_timestamp = crate::util::get_current_timestamp();
+		let pid = std::process::id();
+		let level = record.level().as_str().to_lowercase();
+		eprintln!("{} ({}) [{}] {}", current_timestamp, pid, level, line);
+	}
+
+	fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+///
+/// ロガーを初期化します。
+///
+/// ログレベルは環境変数 `R_GOOGLE_OAUTH2_LOG` で指定します。既定は info です。
+///
+pub fn init() {
+	let level = std::env::var(LOG_LEVEL_ENV)
+		.ok()
+		.and_then(|s| s.parse::<log::LevelFilter>().ok())
+		.unwrap_or(log::LevelFilter::Info);
+	// 二重に初期化された場合は無視します。
+	let _ = log::set_logger(&LOGGER);
+	log::set_max_level(level);
+}
+
+///
+/// "-v" の数だけ、ログレベルを詳細にします。
+///
+/// # Arguments
+/// * `verbosity` - "-v" の数
+///
+pub fn increase_verbosity(verbosity: u8) {
+	let mut level = log::max_level();
+	for _ in 0..verbosity {
+		level = match level {
+			log::LevelFilter::Off => log::LevelFilter::Error,
+			log::LevelFilter::Error => log::LevelFilter::Warn,
+			log::LevelFilter::Warn => log::LevelFilter::Info,
+			log::LevelFilter::Info => log::LevelFilter::Debug,
+			_ => log::LevelFilter::Trace,
+		};
+	}
+	log::set_max_level(level);
+}
+
+///
+/// 文字列に含まれる秘密情報を伏せ字にします。
+///
+/// JSON の `"access_token": "..."`、URL やフォームの `access_token=...`、`Bearer ...` を対象とします。
+///
+pub fn redact(text: &str) -> String {
+	let mut result = text.to_string();
+	for key in SECRET_KEYS {
+		result = redact_json_value(&result, key);
+		result = redact_query_value(&result, key);
+	}
+	result = redact_bearer(&result);
+	return result;
+}
+
+///
+/// JSON の `"key": "value"` の value を伏せ字にします。
+///
+fn redact_json_value(text: &str, key: &str) -> String {
+	let pattern = format!("\"{}\"", key);
+	let mut result = String::new();
+	let mut rest = text;
+	while let Some(position) = rest.find(&pattern) {
+		let (head, tail) = rest.split_at(position + pattern.len());
+		result.push_str(head);
+		rest = tail;
+
+		// : と空白を読み飛ばします。
+		let after_colon = rest.trim_start();
+		if !after_colon.starts_with(':') {
+			continue;
+		}
+		let after_colon = after_colon[1..].trim_start();
+		if !after_colon.starts_with('"') {
+			continue;
+		}
+		// 文字列の終わりを探します。(エスケープを考慮)
+		let body = &after_colon[1..];
+		let mut end = None;
+		let mut escaped = false;
+		for (i, c) in body.char_indices() {
+			if escaped {
+				escaped = false;
+			} else if c == '\\' {
+				escaped = true;
+			} else if c == '"' {
+				end = Some(i);
+				break;
+			}
+		}
+		let end = match end {
+			Some(end) => end,
+			None => continue,
+		};
+		let skipped = rest.len() - after_colon.len();
+		result.push_str(&rest[..skipped]);
+		result.push('"');
+		result.push_str(REDACTED);
+		result.push('"');
+		rest = &body[end + 1..];
+	}
+	result.push_str(rest);
+	return result;
+}
+
+///
+/// URL やフォームの `key=value` の value を伏せ字にします。
+///
+fn redact_query_value(text: &str, key: &str) -> String {
+	let pattern = format!("{}=", key);
+	let mut result = String::new();
+	let mut rest = text;
+	while let Some(position) = rest.find(&pattern) {
+		// 他のキーの一部 (例: "device_code=" の "code=") は対象外です。
+		let previous = rest[..position].chars().last().or_else(|| result.chars().last());
+		let is_boundary = previous
+			.map(|c| c == '?' || c == '&' || c.is_whitespace())
+			.unwrap_or(true);
+		let (head, tail) = rest.split_at(position + pattern.len());
+		result.push_str(head);
+		rest = tail;
+		if !is_boundary {
+			continue;
+		}
+		let end = rest
+			.find(|c: char| c == '&' || c == '#' || c == '"' || c == '\'' || c.is_whitespace())
+			.unwrap_or(rest.len());
+		if 0 < end {
+			result.push_str(REDACTED);
+		}
+		rest = &rest[end..];
+	}
+	result.push_str(rest);
+	return result;
+}
+
+///
+/// `Bearer xxx` のトークンを伏せ字にします。
+///
+fn redact_bearer(text: &str) -> String {
+	let pattern = "Bearer ";
+	let mut result = String::new();
+	let mut rest = text;
+	while let Some(position) = rest.find(pattern) {
+		let (head, tail) = rest.split_at(position + pattern.len());
+		result.push_str(head);
+		rest = tail;
+		let end = rest
+			.find(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+			.unwrap_or(rest.len());
+		if 0 < end {
+			result.push_str(REDACTED);
+		}
+		rest = &rest[end..];
+	}
+	result.push_str(rest);
+	return result;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn redacts_json_string_values_including_nested_ones() {
+		let text = r#"{"access_token": "ya29.a", "expires_in": 3599, "nested": {"tokens": [{"refresh_token":"1//b"}]}, "id_token":"e\"y"}"#;
+
+		assert_eq!(
+			redact(text),
+			r#"{"access_token": "***", "expires_in": 3599, "nested": {"tokens": [{"refresh_token":"***"}]}, "id_token":"***"}"#
+		);
+	}
+
+	#[test]
+	fn keeps_json_keys_that_only_end_with_a_secret_key() {
+		let text = r#"{"user_code":"ABCD-EFGH","device_code":"AH-1","code":"4/0A"}"#;
+
+		assert_eq!(
+			redact(text),
+			r#"{"user_code":"ABCD-EFGH","device_code":"***","code":"***"}"#
+		);
+	}
+
+	#[test]
+	fn redacts_query_strings_up_to_the_end_of_the_value() {
+		assert_eq!(
+			redact("GET /?state=s1&code=4/0Axyz&scope=email HTTP/1.1"),
+			"GET /?state=s1&code=***&scope=email HTTP/1.1"
+		);
+		assert_eq!(
+			redact("http://localhost:8080/?code=4/0Axyz"),
+			"http://localhost:8080/?code=***"
+		);
+		assert_eq!(redact("/?code=4/0A#fragment"), "/?code=***#fragment");
+		assert_eq!(redact("/?code=&state=s1"), "/?code=&state=s1");
+	}
+
+	#[test]
+	fn redacts_form_bodies_without_touching_other_keys() {
+		let body = "grant_type=refresh_token&refresh_token=1//abc&client_id=id&client_secret=s3cr3t";
+		assert_eq!(
+			redact(body),
+			"grant_type=refresh_token&refresh_token=***&client_id=id&client_secret=***"
+		);
+
+		let body =
+			"user_code=ABCD-EFGH&device_code=AH-1&grant_type=urn:ietf:params:oauth:grant-type:device_code";
+		assert_eq!(
+			redact(body),
+			"user_code=ABCD-EFGH&device_code=***&grant_type=urn:ietf:params:oauth:grant-type:device_code"
+		);
+	}
+
+	#[test]
+	fn redacts_bearer_tokens() {
+		assert_eq!(
+			redact("Authorization: Bearer ya29.abc"),
+			"Authorization: Bearer ***"
+		);
+		assert_eq!(
+			redact(r#"["Authorization", "Bearer ya29.abc"] sent"#),
+			r#"["Authorization", "Bearer ***"] sent"#
+		);
+	}
+}
@@ -4,26 +4,31 @@
 //! # References
 //! - [モバイル &デスクトップ アプリ向け OAuth 2.0](https://developers.google.com/identity/protocols/oauth2/native-app?hl=ja)
 
-mod configuration;
-mod gauth2;
-mod util;
+#![allow(clippy::needless_return, clippy::empty_docs)]
+
+use log::{error, info};
+use r_google_oauth2::{
+	adc, arguments, audit, authorization, authorized_client, configuration, gauth2, id_token, logger, retry,
+	store, transport,
+};
 
 /// Rust アプリケーションのエントリーポイント
 fn main() {
-	// client_secret*.json を検出
-	let result = configuration::configure();
+	// ロガーを初期化
+	logger::init();
+
+	// コマンドライン引数を解析
+	let result = arguments::parse();
 	if result.is_err() {
 		let err = result.err().unwrap();
 		error!("{}", err);
 		std::process::exit(1);
 	}
-	let client_secret = result.unwrap();
+	let args = result.unwrap();
+	logger::increase_verbosity(args.verbosity);
 
-	// Google OAuth 2.0 のテスト
-	let result = execute_oauth_example(
-		&client_secret.installed.client_id,
-		&client_secret.installed.client_secret,
-	);
+	// コマンドを実行
+	let result = run(&args);
 	if result.is_err() {
 		let err = result.err().unwrap();
 		error!("{}", err);
@@ -33,13 +38,338 @@ fn main() {
 	info!("Ok.");
 }
 
-/// Google OAuth 2.0 のテスト
-fn execute_oauth_example(client_id: &str, client_secret: &str) -> Result<(), Box<dyn std::error::Error>> {
-	let mut service = crate::gauth2::GoogleOAuth2::new(client_id, client_secret)?;
+/// コマンドを実行します。
+fn run(args: &arguments::Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	match args.command.as_deref() {
+		None => {
+			// client_secret*.json を検出
+			let credentials = configuration::configure(args.client_secret.as_deref())?;
+			// Google OAuth 2.0 のテスト
+			return execute_oauth_example(args, &credentials);
+		}
+		Some("export-adc") => return execute_export_adc(args),
+		Some("print-access-token") => return execute_print_access_token(args),
+		Some("revoke") => return execute_revoke(args),
+		Some("call") => return execute_call(args),
+		Some("inspect") => return execute_inspect(args),
+		Some("encrypt") => return execute_encrypt(args),
+		Some(command) => return Err(format!("不明なコマンドです。[{}]", command).into()),
+	}
+}
+
+/// 資格情報の保存先を開きます。
+fn open_credential_store(
+	args: &arguments::Arguments,
+	kind: store::StoreKind,
+	client_id: &str,
+) -> Result<Box<dyn store::CredentialStore>, Box<dyn std::error::Error>> {
+	let path = args.token_cache.as_deref();
+	let credential_store: Box<dyn store::CredentialStore> = match (kind, path) {
+		(store::StoreKind::File, Some(path)) => Box::new(store::FileStore::new(path)),
+		(store::StoreKind::EncryptedFile, Some(path)) => {
+			let key = store::EncryptionKey::resolve(args.key_file.as_deref())?;
+			Box::new(store::EncryptedFileStore::new(path, key))
+		}
+		(store::StoreKind::File | store::StoreKind::EncryptedFile, None) => {
+			return Err("--token-cache でファイルを指定してください。".into());
+		}
+		#[cfg(feature = "keyring")]
+		(store::StoreKind::Keyring, _) => Box::new(store::KeyringStore::new(client_id)),
+		#[cfg(not(feature = "keyring"))]
+		(store::StoreKind::Keyring, _) => {
+			let _ = client_id;
+			return Err(
+				"--store keyring を使用するには、keyring 機能を有効にしてビルドしてください。".into(),
+			);
+		}
+		(store::StoreKind::Memory, _) => Box::new(store::MemoryStore::new()),
+	};
+	return Ok(credential_store);
+}
+
+/// 監査ログを開きます。--audit-log が指定されていなければ None を返します。
+fn open_audit_log(args: &arguments::Arguments) -> Option<std::sync::Arc<audit::AuditLog>> {
+	let path = args.audit_log.as_deref()?;
+	let max_size = args
+		.audit_log_max_size
+		.unwrap_or(audit::DEFAULT_AUDIT_LOG_MAX_SIZE);
+	return Some(std::sync::Arc::new(audit::AuditLog::new(path, max_size)));
+}
+
+/// 認可手続きを行います。
+///
+/// 監査ログが指定されていれば、トークンのイベントとログインの失敗を記録します。
+fn authorize(
+	args: &arguments::Arguments,
+	credentials: &configuration::Credentials,
+	audit_log: Option<&std::sync::Arc<audit::AuditLog>>,
+) -> Result<gauth2::GoogleOAuth2, Box<dyn std::error::Error>> {
+	let credential_store = match args.store {
+		Some(kind) => Some(open_credential_store(args, kind, credentials.client_id())?),
+		None => None,
+	};
+	let mut service = gauth2::GoogleOAuth2::with_http_options(
+		credentials.client_id(),
+		credentials.client_secret(),
+		&args.http_options,
+	)?;
+	if let Some(credential_store) = credential_store {
+		service.set_credential_store(credential_store);
+	}
+	if let Some(audit_log) = audit_log {
+		service.add_observer(Box::new(audit_log.clone()));
+	}
+	service.set_login_method(args.login_method);
+	service.set_auto_relogin(args.relogin);
+	service.set_allowed_domains(args.allowed_domains.clone());
+	service.set_verification_policy(args.verification_policy.clone());
+	if let Some(endpoint) = &args.tokeninfo_endpoint {
+		service.set_tokeninfo_endpoint(endpoint);
+	}
+	if args.testing_mode {
+		service.set_refresh_token_lifetime(Some(chrono::Duration::days(
+			gauth2::TESTING_MODE_REFRESH_TOKEN_LIFETIME_DAYS,
+		)));
+	}
+
+	if let Err(e) = sign_in(&mut service, args, credentials) {
+		if let Some(audit_log) = audit_log {
+			audit_log.record_login_failure(credentials.client_id(), e.as_ref());
+		}
+		return Err(e);
+	}
+
+	return Ok(service);
+}
+
+/// 保存された資格情報、既存の更新トークン、または認可手続きで、アクセストークンを取得します。
+fn sign_in(
+	service: &mut gauth2::GoogleOAuth2,
+	args: &arguments::Arguments,
+	credentials: &configuration::Credentials,
+) -> Result<(), Box<dyn std::error::Error>> {
+	match credentials {
+		configuration::Credentials::ClientSecret(secret) => {
+			if let Some(redirect_uri) = secret.loopback_redirect_uri()? {
+				service.set_redirect_uri(redirect_uri)?;
+			}
+			service.set_authorization_options(args.authorization_options.clone());
+			let loaded = service.load_credentials()?;
+			let token_data = service.token_data();
+			if loaded && (token_data.refresh_token().is_some() || !token_data.is_expired()) {
+				// ========== 保存された資格情報を使う ==========
+				// 必要であれば更新トークンで更新し、無効になっていれば (--relogin) 認可手続きをやり直します。
+				service.ensure_access_token()?;
+				// 認可手続きを経ないため、許可されたドメインのアカウントであることを改めて確認します。
+				service.verify_allowed_domain()?;
+			} else {
+				// ========== ブラウザーで認可画面を開く ==========
+				// Google OAuth による認可手続きの開始を要求します。
+				service.login()?;
+			}
+		}
+		configuration::Credentials::AuthorizedUser(user) => {
+			// ========== 既存の更新トークンを使う ==========
+			// authorized_user には更新トークンがあるため、ブラウザーを開く必要はありません。
+			service.set_refresh_token(&user.refresh_token);
+			service.refresh_access_token()?;
+			service.verify_allowed_domain()?;
+		}
+	}
+
+	return Ok(());
+}
+
+/// 認可手続きの結果を ADC 形式で書き出します。
+fn execute_export_adc(args: &arguments::Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	// 認可手続きの前に、既存のファイルを上書きしないことを確認します。
+	let path = args.parameters.first().map(std::path::Path::new);
+	let path = adc::export_destination(path, args.force)?;
+
+	let credentials = configuration::configure(args.client_secret.as_deref())?;
+
+	// 更新トークンが必要なため、指定が無ければ access_type=offline, prompt=consent で認可を求めます。
+	let mut args = args.clone();
+	let options = &mut args.authorization_options;
+	if options.access_type.is_none() && options.prompt.is_empty() {
+		let offline = authorization::AuthorizationOptions::offline();
+		options.access_type = offline.access_type;
+		options.prompt = offline.prompt;
+	}
+	let service = authorize(&args, &credentials, open_audit_log(&args).as_ref())?;
+
+	let path = adc::export(&service, Some(&path), args.force)?;
+	info!("ADC を書き出しました。[{}]", path.display());
+
+	return Ok(());
+}
+
+/// 認可済みの HTTP クライアントで、任意の Google API を呼び出します。
+///
+/// `call URL` は GET を、`call URL JSON` は JSON を本文とする POST を送信します。
+fn execute_call(args: &arguments::Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	let url = args.parameters.first().ok_or("URL を指定してください。")?;
+
+	let credentials = configuration::configure(args.client_secret.as_deref())?;
+	let service = authorize(args, &credentials, open_audit_log(args).as_ref())?;
+	let mut client = authorized_client::AuthorizedClient::new(service);
 
-	// ========== ブラウザーで認可画面を開く ==========
-	// Google OAuth による認可手続きの開始を要求します。
-	service.begin()?;
+	let response = match args.parameters.get(1) {
+		Some(body) => client.post_json(url, &serde_json::from_str(body)?)?,
+		None => client.get(url)?,
+	};
+	info!("GOOGLE> HTTP {}", response.status);
+	println!("{}", response.body);
+	if !response.is_success() {
+		return Err(format!("HTTP {}", response.status).into());
+	}
+
+	return Ok(());
+}
+
+/// authorized_user の更新トークンを無効にします。
+fn execute_revoke(args: &arguments::Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	let credentials = configuration::configure(args.client_secret.as_deref())?;
+	let user = match &credentials {
+		configuration::Credentials::AuthorizedUser(user) => user,
+		_ => {
+			return Err(
+				"無効にする更新トークンがありません。authorized_user 形式のファイルを指定してください。"
+					.into(),
+			)
+		}
+	};
+
+	let mut service =
+		gauth2::GoogleOAuth2::with_http_options(&user.client_id, &user.client_secret, &args.http_options)?;
+	if let Some(audit_log) = open_audit_log(args) {
+		service.add_observer(Box::new(audit_log));
+	}
+	service.set_refresh_token(&user.refresh_token);
+	service.revoke()?;
+	info!("更新トークンを無効にしました。");
+
+	return Ok(());
+}
+
+/// ADC の検索順に従ってアクセストークンを取得し、標準出力に書き出します。
+fn execute_print_access_token(args: &arguments::Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	let mut provider = adc::resolve(&args.http_options)?;
+	let access_token = provider.get_access_token()?;
+	println!("{}", access_token);
+
+	return Ok(());
+}
+
+/// 平文で保存されたトークンのファイルを暗号化します。
+///
+/// `encrypt PATH` はファイルを置き換え、`encrypt PATH OUTPUT` は別のファイルに書き出します。
+fn execute_encrypt(args: &arguments::Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	use store::CredentialStore;
+
+	let input = args
+		.parameters
+		.first()
+		.ok_or("暗号化するファイルを指定してください。")?;
+	let input = std::path::Path::new(input);
+	let output = args.parameters.get(1).map(std::path::Path::new).unwrap_or(input);
+
+	let token_data = store::FileStore::new(input)
+		.load()?
+		.ok_or_else(|| format!("ファイルがみつかりません。[{}]", input.display()))?;
+	let key = store::EncryptionKey::resolve(args.key_file.as_deref())?;
+	store::EncryptedFileStore::new(output, key).save(&token_data)?;
+	info!("暗号化しました。[{}]", output.display());
+
+	return Ok(());
+}
+
+/// UNIX 時刻を、ローカル時刻の文字列に変換します。
+fn format_timestamp(seconds: i64) -> String {
+	use chrono::TimeZone;
+
+	return match chrono::Local.timestamp_opt(seconds, 0).single() {
+		Some(t) => t.format("%Y-%m-%d %H:%M:%S %:z").to_string(),
+		None => seconds.to_string(),
+	};
+}
+
+/// トークンの内容を表示します。
+///
+/// JWT であればヘッダーとクレームをその場で復号し、`--verify remote` では tokeninfo エンドポイントで、
+/// `--verify jwks` では Google の公開鍵で署名を確認します。JWT でなければアクセストークンとして扱います。
+fn execute_inspect(args: &arguments::Arguments) -> Result<(), Box<dyn std::error::Error>> {
+	let token = args.parameters.first().ok_or("トークンを指定してください。")?;
+
+	let kind = match id_token::decode_jwt(token) {
+		Ok((header, claims)) => {
+			println!("header: {}", serde_json::to_string_pretty(&header)?);
+			println!("claims: {}", serde_json::to_string_pretty(&claims)?);
+			for name in ["iat", "nbf", "auth_time"] {
+				if let Some(t) = claims.get(name).and_then(|v| v.as_i64()) {
+					println!("{}: {}", name, format_timestamp(t));
+				}
+			}
+			if let Some(exp) = claims.get("exp").and_then(|v| v.as_i64()) {
+				let remaining = exp - chrono::Utc::now().timestamp();
+				let state = if 0 < remaining {
+					format!("あと {} 秒", remaining)
+				} else {
+					format!("{} 秒前に期限切れ", -remaining)
+				};
+				println!("exp: {} ({})", format_timestamp(exp), state);
+			}
+			gauth2::TokenKind::IdToken
+		}
+		Err(e) => {
+			info!("JWT ではないため、アクセストークンとして扱います。({})", e);
+			gauth2::TokenKind::AccessToken
+		}
+	};
+
+	let verification = match args.inspect_verification {
+		Some(verification) => verification,
+		None => return Ok(()),
+	};
+	let transport = transport::ReqwestTransport::with_options(&args.http_options)?;
+	let retry_policy = retry::RetryPolicy::default();
+	match verification {
+		arguments::InspectVerification::Remote => {
+			let endpoint = args
+				.tokeninfo_endpoint
+				.as_deref()
+				.unwrap_or(gauth2::DEFAULT_TOKENINFO_ENDPOINT);
+			let token_info = gauth2::query_tokeninfo(&transport, &retry_policy, endpoint, kind, token)?;
+			println!("tokeninfo: {}", serde_json::to_string_pretty(&token_info)?);
+		}
+		arguments::InspectVerification::Jwks => {
+			if kind != gauth2::TokenKind::IdToken {
+				return Err("JWKS で検証できるのは ID トークンだけです。".into());
+			}
+			let jwks = gauth2::fetch_google_jwks(&transport, &retry_policy)?;
+			let claims = id_token::verify(token, &jwks, None, chrono::Utc::now(), chrono::Duration::zero())?;
+			println!("signature: 有効 (iss: {}, aud: {})", claims.iss, claims.aud);
+		}
+	}
+
+	return Ok(());
+}
+
+/// Google OAuth 2.0 のテスト
+fn execute_oauth_example(
+	args: &arguments::Arguments,
+	credentials: &configuration::Credentials,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let audit_log = open_audit_log(args);
+	let mut service = authorize(args, credentials, audit_log.as_ref())?;
+	if let Some(expires_at) = service.token_data().expires_at() {
+		info!(
+			"アクセストークンの有効期限: {}",
+			expires_at
+				.with_timezone(&chrono::Local)
+				.format("%Y-%m-%d %H:%M:%S")
+		);
+	}
 
 	// ========== アクセストークンの確認 >> Google API ==========
 	info!("セッションの妥当性を確認しています...");
@@ -53,6 +383,9 @@ fn execute_oauth_example(client_id: &str, client_secret: &str) -> Result<(), Box
 		"GOOGLE> user_profile: {}",
 		serde_json::to_string_pretty(&user_profile)?
 	);
+	if let Some(audit_log) = &audit_log {
+		audit_log.record_login(credentials.client_id(), &user_profile, service.token_data());
+	}
 
 	return Ok(());
 }
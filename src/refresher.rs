@@ -0,0 +1,422 @@
+//!
+//! アクセストークンのバックグラウンド更新
+//!
+//! 長時間動作するサービスのために、アクセストークンの有効期限の少し前に別のスレッドで更新し、
+//! 新しいアクセストークンを [`TokenWatch`] で公開します。
+//! 読み込む側は公開された値を参照するだけなので、更新の通信を待ちません。
+
+use crate::gauth2::{GoogleOAuth2, TokenData};
+use crate::retry::RetryPolicy;
+use log::{debug, info, warn};
+
+/// 有効期限のこの時間前に更新します (既定)
+const DEFAULT_REFRESH_MARGIN_SECONDS: i64 = 300;
+
+/// 更新に成功した後、次の更新までの最短の時間 (有効期間より長い margin で更新を繰り返さないため)
+const MIN_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+///
+/// バックグラウンド更新の設定
+///
+#[derive(Debug, Clone)]
+pub struct RefresherOptions {
+	/// 有効期限のこの時間前に更新します。
+	pub margin: chrono::Duration,
+	/// 失敗した場合の、初回の待機時間
+	pub initial_backoff: std::time::Duration,
+	/// 失敗した場合の、待機時間の上限
+	pub max_backoff: std::time::Duration,
+}
+
+impl Default for RefresherOptions {
+	fn default() -> Self {
+		return Self {
+			margin: chrono::Duration::seconds(DEFAULT_REFRESH_MARGIN_SECONDS),
+			initial_backoff: std::time::Duration::from_secs(5),
+			max_backoff: std::time::Duration::from_secs(300),
+		};
+	}
+}
+
+///
+/// 公開されたアクセストークン
+///
+#[derive(Debug, Clone)]
+pub struct PublishedToken {
+	/// アクセストークン
+	pub access_token: String,
+	/// 有効期限
+	pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+///
+/// スレッド間で共有する状態
+///
+#[derive(Default)]
+struct State {
+	/// 現在のアクセストークン
+	token: Option<PublishedToken>,
+	/// アクセストークンを公開するたびに増える番号
+	version: u64,
+	/// 直前の更新の失敗の理由 (成功すると None に戻ります)
+	last_error: Option<String>,
+	/// 停止を求められたかどうか
+	shutdown: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+	state: std::sync::Mutex<State>,
+	changed: std::sync::Condvar,
+}
+
+impl Shared {
+	fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+		return self.state.lock().unwrap_or_else(|e| e.into_inner());
+	}
+
+	///
+	/// アクセストークンを公開し、待っている読み込み側に知らせます。
+	///
+	fn publish(&self, token_data: &TokenData) {
+		let mut state = self.lock();
+		state.token = Some(PublishedToken {
+			access_token: token_data.access_token.clone(),
+			expires_at: token_data.expires_at(),
+		});
+		state.version += 1;
+		state.last_error = None;
+		drop(state);
+		self.changed.notify_all();
+	}
+}
+
+///
+/// 公開されたアクセストークンを参照するもの
+///
+/// 複製して、複数のスレッドで使用できます。
+///
+#[derive(Clone)]
+pub struct TokenWatch {
+	shared: std::sync::Arc<Shared>,
+	/// 最後に参照した番号
+	seen: u64,
+}
+
+impl TokenWatch {
+	///
+	/// 現在のアクセストークンを返します。まだ公開されていなければ None を返します。
+	///
+	pub fn current(&mut self) -> Option<PublishedToken> {
+		let state = self.shared.lock();
+		self.seen = state.version;
+		return state.token.clone();
+	}
+
+	///
+	/// 最後に参照した後にアクセストークンが更新されたかどうかを返します。
+	///
+	pub fn has_changed(&self) -> bool {
+		return self.seen != self.shared.lock().version;
+	}
+
+	///
+	/// アクセストークンが更新されるまで待ちます。
+	///
+	/// 更新されれば新しいアクセストークンを、時間切れか停止した場合は None を返します。
+	///
+	/// # Arguments
+	/// * `timeout` - 待つ時間の上限
+	///
+	pub fn wait_for_change(&mut self, timeout: std::time::Duration) -> Option<PublishedToken> {
+		let state = self.shared.lock();
+		let (state, _) = self
+			.shared
+			.changed
+			.wait_timeout_while(state, timeout, |s| s.version == self.seen && !s.shutdown)
+			.unwrap_or_else(|e| e.into_inner());
+		if state.version == self.seen {
+			return None;
+		}
+		self.seen = state.version;
+		return state.token.clone();
+	}
+
+	///
+	/// 直前の更新が失敗していれば、その理由を返します。
+	///
+	pub fn last_error(&self) -> Option<String> {
+		return self.shared.lock().last_error.clone();
+	}
+}
+
+///
+/// アクセストークンをバックグラウンドで更新するもの
+///
+/// ドロップすると、スレッドを停止して終了を待ちます。
+///
+pub struct BackgroundRefresher {
+	shared: std::sync::Arc<Shared>,
+	handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl BackgroundRefresher {
+	///
+	/// 更新のスレッドを開始します。
+	///
+	/// `service` には、更新トークンを持つ認可済みのインスタンスを渡してください。
+	/// スレッドは更新の間だけ `service` をロックします。
+	///
+	/// # Arguments
+	/// * `service` - 共有する GoogleOAuth2
+	/// * `options` - 設定
+	///
+	pub fn spawn(
+		service: std::sync::Arc<std::sync::Mutex<GoogleOAuth2>>,
+		options: RefresherOptions,
+	) -> Result<Self, Box<dyn std::error::Error>> {
+		let shared = std::sync::Arc::new(Shared::default());
+
+		// 現在のアクセストークンがまだ使えれば、読み込む側がすぐに使えるように公開しておきます。
+		let token_data = service
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.token_data()
+			.clone();
+		if !token_data.expires_within(chrono::Duration::zero()) {
+			shared.publish(&token_data);
+		}
+
+		let thread_shared = shared.clone();
+		let handle = std::thread::Builder::new()
+			.name("token-refresher".to_string())
+			.spawn(move || run(&service, &options, &thread_shared))?;
+		return Ok(Self {
+			shared,
+			handle: Some(handle),
+		});
+	}
+
+	///
+	/// 公開されたアクセストークンを参照するものを返します。
+	///
+	pub fn watch(&self) -> TokenWatch {
+		return TokenWatch {
+			shared: self.shared.clone(),
+			seen: 0,
+		};
+	}
+
+	///
+	/// スレッドを停止し、終了を待ちます。
+	///
+	pub fn shutdown(mut self) {
+		self.stop();
+	}
+
+	fn stop(&mut self) {
+		self.shared.lock().shutdown = true;
+		self.shared.changed.notify_all();
+		if let Some(handle) = self.handle.take() {
+			let _ = handle.join();
+		}
+	}
+}
+
+impl Drop for BackgroundRefresher {
+	fn drop(&mut self) {
+		self.stop();
+	}
+}
+
+///
+/// 更新のスレッドの本体
+///
+fn run(service: &std::sync::Mutex<GoogleOAuth2>, options: &RefresherOptions, shared: &Shared) {
+	let backoff = RetryPolicy {
+		initial_backoff: options.initial_backoff,
+		max_backoff: options.max_backoff,
+		..RetryPolicy::default()
+	};
+	let mut failures: u32 = 0;
+	let mut refreshed = false;
+	let mut token_data = service
+		.lock()
+		.unwrap_or_else(|e| e.into_inner())
+		.token_data()
+		.clone();
+
+	loop {
+		let delay = if 0 < failures {
+			backoff.backoff(failures)
+		} else if refreshed {
+			next_refresh_delay(&token_data, options.margin).max(MIN_REFRESH_INTERVAL)
+		} else {
+			next_refresh_delay(&token_data, options.margin)
+		};
+		debug!("{} 秒後にアクセストークンを更新します。", delay.as_secs());
+
+		// ========== 次の更新まで待つ ==========
+		let state = shared.lock();
+		let (state, _) = shared
+			.changed
+			.wait_timeout_while(state, delay, |s| !s.shutdown)
+			.unwrap_or_else(|e| e.into_inner());
+		if state.shutdown {
+			info!("アクセストークンのバックグラウンド更新を停止しました。");
+			return;
+		}
+		drop(state);
+
+		// ========== 更新 ==========
+		let result = {
+			let mut service = service.lock().unwrap_or_else(|e| e.into_inner());
+			service
+				.refresh_access_token()
+				.map(|_| service.token_data().clone())
+		};
+		match result {
+			Ok(new_token_data) => {
+				failures = 0;
+				refreshed = true;
+				token_data = new_token_data;
+				shared.publish(&token_data);
+			}
+			Err(e) => {
+				failures = failures.saturating_add(1);
+				warn!(
+					"アクセストークンを更新できません。({} 回目) 理由: {}",
+					failures, e
+				);
+				shared.lock().last_error = Some(e.to_string());
+			}
+		}
+	}
+}
+
+///
+/// 次の更新までの時間を返します。
+///
+fn next_refresh_delay(token_data: &TokenData, margin: chrono::Duration) -> std::time::Duration {
+	let refresh_at = match token_data.expires_at() {
+		Some(expires_at) => expires_at - margin,
+		None => return std::time::Duration::ZERO,
+	};
+	return (refresh_at - chrono::Utc::now())
+		.to_std()
+		.unwrap_or(std::time::Duration::ZERO);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gauth2::tests::mock_service;
+	use crate::transport::MockTransport;
+
+	/// 有効期間が 3600 秒のアクセストークンのレスポンス
+	fn token_response(access_token: &str) -> String {
+		return format!(r#"{{"access_token":"{}","expires_in":3600}}"#, access_token);
+	}
+
+	/// アクセストークンを取得済みの、共有する GoogleOAuth2 を返します。
+	fn authorized_service() -> (
+		std::sync::Arc<std::sync::Mutex<GoogleOAuth2>>,
+		std::sync::Arc<MockTransport>,
+	) {
+		let (mut service, transport) = mock_service();
+		service.set_retry_policy(RetryPolicy::none());
+		service.set_refresh_token("rt");
+		transport.push_response(200, &token_response("at1"));
+		service.refresh_access_token().unwrap();
+		return (std::sync::Arc::new(std::sync::Mutex::new(service)), transport);
+	}
+
+	#[test]
+	fn schedules_the_refresh_margin_before_the_expiry() {
+		let (service, _) = authorized_service();
+		let token_data = service.lock().unwrap().token_data().clone();
+
+		let delay = next_refresh_delay(&token_data, chrono::Duration::seconds(300));
+		assert!((3290..=3300).contains(&delay.as_secs()));
+		let delay = next_refresh_delay(&token_data, chrono::Duration::seconds(4000));
+		assert_eq!(delay, std::time::Duration::ZERO);
+		assert_eq!(
+			next_refresh_delay(&TokenData::default(), chrono::Duration::seconds(300)),
+			std::time::Duration::ZERO
+		);
+	}
+
+	#[test]
+	fn publishes_the_current_token_and_refreshes_at_the_margin() {
+		let (service, transport) = authorized_service();
+		transport.push_response(200, &token_response("at2"));
+		let options = RefresherOptions {
+			margin: chrono::Duration::seconds(3599),
+			..Default::default()
+		};
+
+		let refresher = BackgroundRefresher::spawn(service, options).unwrap();
+		let mut watch = refresher.watch();
+
+		assert_eq!(watch.current().unwrap().access_token, "at1");
+		assert!(!watch.has_changed());
+		assert_eq!(transport.requests().len(), 2);
+		let token = watch.wait_for_change(std::time::Duration::from_secs(5)).unwrap();
+		assert_eq!(token.access_token, "at2");
+		assert!(token.expires_at.is_some());
+		assert_eq!(transport.requests().len(), 3);
+		// 次の更新は、新しいアクセストークンの有効期限の margin 前です。
+		assert!(watch
+			.wait_for_change(std::time::Duration::from_millis(200))
+			.is_none());
+	}
+
+	#[test]
+	fn backs_off_after_a_failed_refresh() {
+		let (service, transport) = authorized_service();
+		transport.push_error("connection reset");
+		transport.push_response(200, &token_response("at2"));
+		let options = RefresherOptions {
+			margin: chrono::Duration::seconds(3600),
+			initial_backoff: std::time::Duration::from_millis(300),
+			max_backoff: std::time::Duration::from_millis(300),
+		};
+
+		let refresher = BackgroundRefresher::spawn(service, options).unwrap();
+		let mut watch = refresher.watch();
+		watch.current();
+
+		let started = std::time::Instant::now();
+		while watch.last_error().is_none() {
+			assert!(started.elapsed() < std::time::Duration::from_secs(5));
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		let failed = std::time::Instant::now();
+		assert!(watch.last_error().unwrap().contains("connection reset"));
+		assert_eq!(watch.current().unwrap().access_token, "at1");
+
+		// 待機時間には 0.5 倍から 1.0 倍のゆらぎが加わります。
+		let token = watch.wait_for_change(std::time::Duration::from_secs(5)).unwrap();
+		assert_eq!(token.access_token, "at2");
+		assert!(std::time::Duration::from_millis(100) <= failed.elapsed());
+		assert!(watch.last_error().is_none());
+		assert_eq!(transport.requests().len(), 4);
+	}
+
+	#[test]
+	fn stops_the_thread_when_dropped() {
+		let (service, transport) = authorized_service();
+		let refresher = BackgroundRefresher::spawn(service, RefresherOptions::default()).unwrap();
+		let mut watch = refresher.watch();
+		watch.current();
+
+		let started = std::time::Instant::now();
+		drop(refresher);
+		assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+		// 停止した後は、更新を待たずに戻ります。
+		assert!(watch.wait_for_change(std::time::Duration::from_secs(5)).is_none());
+		assert!(started.elapsed() < std::time::Duration::from_secs(1));
+		assert_eq!(transport.requests().len(), 2);
+	}
+}
@@ -0,0 +1,275 @@
+//!
+//! 一時的な失敗に対する再試行
+//!
+//! 5xx, 429 と通信エラーを一時的な失敗とみなし、指数バックオフで再試行します。
+//! 認可コードは一度しか使えないため、トークンへの変換には使用しません。
+
+use crate::transport::{HttpRequest, HttpResponse, HttpTransport};
+use log::warn;
+
+///
+/// 再試行の方針
+///
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+	/// 最大の試行回数 (初回を含む)
+	pub max_attempts: u32,
+	/// 初回の待機時間
+	pub initial_backoff: std::time::Duration,
+	/// 待機時間の上限 (Retry-After もこの値で打ち切ります)
+	pub max_backoff: std::time::Duration,
+	/// 待機時間の倍率 (1.0 未満や有限でない値は 1.0 として扱います)
+	pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		return Self {
+			max_attempts: 3,
+			initial_backoff: std::time::Duration::from_millis(500),
+			max_backoff: std::time::Duration::from_secs(30),
+			multiplier: 2.0,
+		};
+	}
+}
+
+impl RetryPolicy {
+	///
+	/// 再試行しない方針を返します。
+	///
+	pub fn none() -> Self {
+		return Self {
+			max_attempts: 1,
+			..Self::default()
+		};
+	}
+
+	///
+	/// n 回目 (1 始まり) の失敗の後の待機時間を返します。
+	///
+	/// 指数バックオフの値に、0.5 倍から 1.0 倍のゆらぎを加えます。
+	///
+	pub fn backoff(&self, attempt: u32) -> std::time::Duration {
+		use rand::Rng;
+
+		let jitter: f64 = rand::thread_rng().gen_range(0.5..=1.0);
+		return self.backoff_with_jitter(attempt, jitter);
+	}
+
+	///
+	/// 指定したゆらぎで、n 回目 (1 始まり) の失敗の後の待機時間を返します。
+	///
+	/// 待機時間は max_backoff を超えません。
+	///
+	fn backoff_with_jitter(&self, attempt: u32, jitter: f64) -> std::time::Duration {
+		let multiplier = if self.multiplier.is_finite() {
+			self.multiplier.max(1.0)
+		} else {
+			1.0
+		};
+		let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+		let base = self.initial_backoff.as_secs_f64() * multiplier.powi(exponent);
+		let base = base.min(self.max_backoff.as_secs_f64());
+		let wait = std::time::Duration::try_from_secs_f64(base * jitter).unwrap_or(self.max_backoff);
+		return wait.min(self.max_backoff);
+	}
+
+	///
+	/// リクエストを送信し、一時的な失敗であれば再試行します。
+	///
+	/// 冪等なリクエストにのみ使用してください。
+	///
+	/// # Arguments
+	/// * `transport` - HTTP 通信を行うもの
+	/// * `request` - リクエスト
+	///
+	pub fn send(
+		&self,
+		transport: &dyn HttpTransport,
+		request: &HttpRequest,
+	) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		let max_attempts = self.max_attempts.max(1);
+		let mut attempt = 1;
+		loop {
+			let result = transport.send(request);
+			let wait = match &result {
+				Ok(response) if is_retryable_status(response.status) => {
+					let retry_after = response.header("Retry-After").and_then(parse_retry_after);
+					warn!(
+						"一時的なエラーです。[HTTP {}] ({}/{}) {}",
+						response.status, attempt, max_attempts, request.url
+					);
+					retry_after
+						.map(|d| d.min(self.max_backoff))
+						.unwrap_or(self.backoff(attempt))
+				}
+				Ok(_) => return result,
+				Err(e) => {
+					warn!(
+						"通信エラーです。({}/{}) {} 理由: {}",
+						attempt, max_attempts, request.url, e
+					);
+					self.backoff(attempt)
+				}
+			};
+			if max_attempts <= attempt {
+				return result;
+			}
+			std::thread::sleep(wait);
+			attempt += 1;
+		}
+	}
+}
+
+///
+/// 再試行するべきステータスコードかどうかを返します。
+///
+fn is_retryable_status(status: u16) -> bool {
+	return status == 408
+		|| status == 429
+		|| status == 500
+		|| status == 502
+		|| status == 503
+		|| status == 504;
+}
+
+///
+/// Retry-After ヘッダー (秒数または HTTP-date) を解析します。
+///
+fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+	let value = value.trim();
+	if let Ok(seconds) = value.parse::<u64>() {
+		return Some(std::time::Duration::from_secs(seconds));
+	}
+	let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+	let wait = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+	return Some(wait.to_std().unwrap_or_default());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::transport::MockTransport;
+
+	const URL: &str = "https://oauth2.googleapis.com/token";
+
+	/// 待機しない方針
+	fn immediate(max_attempts: u32) -> RetryPolicy {
+		return RetryPolicy {
+			max_attempts,
+			initial_backoff: std::time::Duration::ZERO,
+			max_backoff: std::time::Duration::ZERO,
+			..RetryPolicy::default()
+		};
+	}
+
+	#[test]
+	fn retryable_statuses_are_timeouts_rate_limits_and_server_errors() {
+		for status in [408, 429, 500, 502, 503, 504] {
+			assert!(is_retryable_status(status), "{}", status);
+		}
+		for status in [200, 400, 401, 403, 404, 501, 505] {
+			assert!(!is_retryable_status(status), "{}", status);
+		}
+	}
+
+	#[test]
+	fn parse_retry_after_accepts_seconds_and_http_dates() {
+		assert_eq!(
+			parse_retry_after(" 120 "),
+			Some(std::time::Duration::from_secs(120))
+		);
+		assert_eq!(parse_retry_after("0"), Some(std::time::Duration::ZERO));
+
+		let future = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+		let wait = parse_retry_after(&future).unwrap();
+		assert!(std::time::Duration::from_secs(55) < wait && wait <= std::time::Duration::from_secs(60));
+
+		// 過去の日時は待機しません。
+		assert_eq!(
+			parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+			Some(std::time::Duration::ZERO)
+		);
+
+		assert_eq!(parse_retry_after("-1"), None);
+		assert_eq!(parse_retry_after("soon"), None);
+	}
+
+	#[test]
+	fn backoff_grows_exponentially_up_to_max_backoff() {
+		let policy = RetryPolicy::default();
+		let secs = |attempt, jitter| policy.backoff_with_jitter(attempt, jitter).as_secs_f64();
+
+		assert_eq!(secs(1, 1.0), 0.5);
+		assert_eq!(secs(2, 1.0), 1.0);
+		assert_eq!(secs(3, 1.0), 2.0);
+		assert_eq!(secs(3, 0.5), 1.0);
+		assert_eq!(secs(10, 1.0), 30.0);
+		assert_eq!(secs(u32::MAX, 1.0), 30.0);
+		assert_eq!(secs(u32::MAX, 0.5), 15.0);
+
+		for attempt in 1..10 {
+			let wait = policy.backoff(attempt);
+			assert!(policy.backoff_with_jitter(attempt, 0.5) <= wait);
+			assert!(wait <= policy.backoff_with_jitter(attempt, 1.0));
+		}
+	}
+
+	#[test]
+	fn backoff_does_not_panic_on_invalid_multipliers() {
+		for multiplier in [-2.0, 0.0, 0.5, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+			let policy = RetryPolicy {
+				multiplier,
+				..RetryPolicy::default()
+			};
+			assert_eq!(policy.backoff_with_jitter(1, 1.0), policy.initial_backoff);
+			assert_eq!(policy.backoff_with_jitter(5, 1.0), policy.initial_backoff);
+		}
+
+		let policy = RetryPolicy {
+			max_backoff: std::time::Duration::MAX,
+			multiplier: f64::MAX,
+			..RetryPolicy::default()
+		};
+		assert_eq!(policy.backoff_with_jitter(3, 1.0), std::time::Duration::MAX);
+	}
+
+	#[test]
+	fn send_retries_transient_failures_until_success() {
+		let transport = MockTransport::new();
+		transport.push_response(503, "{}");
+		transport.push_error("connection reset");
+		transport.push_response(200, r#"{"ok":true}"#);
+
+		let response = immediate(3).send(&transport, &HttpRequest::get(URL)).unwrap();
+		assert_eq!(response.status, 200);
+		assert_eq!(transport.requests().len(), 3);
+	}
+
+	#[test]
+	fn send_stops_at_max_attempts() {
+		let transport = MockTransport::new();
+		transport.push_response(500, "{}");
+		transport.push_response(502, "{}");
+		transport.push_response(200, "{}");
+
+		let response = immediate(2).send(&transport, &HttpRequest::get(URL)).unwrap();
+		assert_eq!(response.status, 502);
+		assert_eq!(transport.requests().len(), 2);
+
+		let transport = MockTransport::new();
+		transport.push_error("connection refused");
+		assert!(immediate(0).send(&transport, &HttpRequest::get(URL)).is_err());
+		assert_eq!(transport.requests().len(), 1);
+	}
+
+	#[test]
+	fn send_does_not_retry_client_errors() {
+		let transport = MockTransport::new();
+		transport.push_response(400, r#"{"error":"invalid_grant"}"#);
+
+		let response = immediate(3).send(&transport, &HttpRequest::get(URL)).unwrap();
+		assert_eq!(response.status, 400);
+		assert_eq!(transport.requests().len(), 1);
+	}
+}
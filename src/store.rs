@@ -0,0 +1,735 @@
+//!
+//! 資格情報 (TokenData) の保存先
+//!
+
+use crate::gauth2::TokenData;
+use crate::lock::CredentialLock;
+use crate::util;
+
+///
+/// 資格情報の保存先
+///
+pub trait CredentialStore: Send {
+	///
+	/// 保存された資格情報を読み込みます。保存されていなければ None を返します。
+	///
+	fn load(&self) -> Result<Option<TokenData>, Box<dyn std::error::Error>>;
+
+	///
+	/// 資格情報を保存します。
+	///
+	fn save(&self, token_data: &TokenData) -> Result<(), Box<dyn std::error::Error>>;
+
+	///
+	/// 保存された資格情報を削除します。
+	///
+	fn delete(&self) -> Result<(), Box<dyn std::error::Error>>;
+
+	///
+	/// 他のプロセスと共有する保存先であれば、読み込み、更新、書き込みの間のロックを取得します。
+	///
+	/// 既定では何もせずに None を返します。
+	///
+	fn lock(&self) -> Result<Option<CredentialLock>, Box<dyn std::error::Error>> {
+		return Ok(None);
+	}
+}
+
+///
+/// 資格情報の保存先の種類
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+	/// JSON ファイル
+	File,
+	/// 暗号化した JSON ファイル
+	EncryptedFile,
+	/// Secret Service (D-Bus のキーリング)
+	Keyring,
+	/// メモリー
+	Memory,
+}
+
+impl std::str::FromStr for StoreKind {
+	type Err = Box<dyn std::error::Error>;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		return match s {
+			"file" => Ok(StoreKind::File),
+			"encrypted-file" => Ok(StoreKind::EncryptedFile),
+			"keyring" => Ok(StoreKind::Keyring),
+			"memory" => Ok(StoreKind::Memory),
+			_ => Err(format!("不明な保存先です。[{}]", s).into()),
+		};
+	}
+}
+
+///
+/// メモリー上の保存先 (プロセスの終了とともに失われます)
+///
+#[derive(Default)]
+pub struct MemoryStore {
+	data: std::sync::Mutex<Option<String>>,
+}
+
+impl MemoryStore {
+	///
+	/// 新しいインスタンスを返します。
+	///
+	pub fn new() -> Self {
+		return Self::default();
+	}
+}
+
+impl CredentialStore for MemoryStore {
+	fn load(&self) -> Result<Option<TokenData>, Box<dyn std::error::Error>> {
+		let data = self.data.lock().unwrap();
+		return match data.as_ref() {
+			Some(json) => Ok(Some(serde_json::from_str(json)?)),
+			None => Ok(None),
+		};
+	}
+
+	fn save(&self, token_data: &TokenData) -> Result<(), Box<dyn std::error::Error>> {
+		let json = serde_json::to_string(token_data)?;
+		*self.data.lock().unwrap() = Some(json);
+		return Ok(());
+	}
+
+	fn delete(&self) -> Result<(), Box<dyn std::error::Error>> {
+		*self.data.lock().unwrap() = None;
+		return Ok(());
+	}
+}
+
+///
+/// JSON ファイルの保存先
+///
+pub struct FileStore {
+	path: std::path::PathBuf,
+}
+
+impl FileStore {
+	///
+	/// 新しいインスタンスを返します。
+	///
+	/// # Arguments
+	/// * `path` - ファイルパス
+	///
+	pub fn new(path: &std::path::Path) -> Self {
+		return Self {
+			path: path.to_path_buf(),
+		};
+	}
+}
+
+impl CredentialStore for FileStore {
+	fn load(&self) -> Result<Option<TokenData>, Box<dyn std::error::Error>> {
+		if !self.path.is_file() {
+			return Ok(None);
+		}
+		let text = std::fs::read_to_string(&self.path)?;
+		let token_data: TokenData = serde_json::from_str(&text)
+			.map_err(|e| format!("{} を解析できません。理由: {}", self.path.display(), e))?;
+		return Ok(Some(token_data));
+	}
+
+	fn save(&self, token_data: &TokenData) -> Result<(), Box<dyn std::error::Error>> {
+		let json = serde_json::to_string_pretty(token_data)?;
+		util::write_secret_file(&self.path, json.as_bytes())?;
+		return Ok(());
+	}
+
+	fn delete(&self) -> Result<(), Box<dyn std::error::Error>> {
+		if self.path.exists() {
+			std::fs::remove_file(&self.path)?;
+		}
+		return Ok(());
+	}
+
+	fn lock(&self) -> Result<Option<CredentialLock>, Box<dyn std::error::Error>> {
+		return Ok(Some(CredentialLock::acquire(&self.path)?));
+	}
+}
+
+/// パスフレーズを指定する環境変数
+pub const PASSPHRASE_ENV: &str = "R_GOOGLE_OAUTH2_PASSPHRASE";
+
+/// 暗号化ファイルの形式名
+const ENCRYPTED_FILE_FORMAT: &str = "r-google-oauth2/encrypted-token";
+
+/// 暗号化ファイルの形式のバージョン
+const ENCRYPTED_FILE_VERSION: u32 = 1;
+
+/// 鍵の長さ (バイト)
+const KEY_LENGTH: usize = 32;
+
+/// XChaCha20-Poly1305 の nonce の長さ (バイト)
+const NONCE_LENGTH: u32 = 24;
+
+/// Argon2 の salt の長さ (バイト)
+const SALT_LENGTH: u32 = 16;
+
+///
+/// 暗号化の鍵
+///
+#[derive(Clone)]
+pub enum EncryptionKey {
+	/// パスフレーズから Argon2id で導出します。
+	Passphrase(String),
+	/// 鍵ファイル (32 バイトのバイナリ、または BASE64 のテキスト) を使用します。
+	KeyFile(std::path::PathBuf),
+}
+
+impl std::fmt::Debug for EncryptionKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		return match self {
+			EncryptionKey::Passphrase(_) => write!(f, "Passphrase(***)"),
+			EncryptionKey::KeyFile(path) => write!(f, "KeyFile({})", path.display()),
+		};
+	}
+}
+
+///
+/// Argon2id のパラメーター
+///
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug, Clone)]
+struct KdfParameters {
+	/// salt (BASE64)
+	salt: String,
+	/// メモリー (KiB)
+	m_cost: u32,
+	/// 反復回数
+	t_cost: u32,
+	/// 並列度
+	p_cost: u32,
+}
+
+///
+/// 暗号化ファイルの内容
+///
+#[derive(serde_derive::Serialize, serde_derive::Deserialize, Debug)]
+struct EncryptedFile {
+	/// 形式名
+	format: String,
+	/// 形式のバージョン
+	version: u32,
+	/// 暗号方式
+	cipher: String,
+	/// 鍵の導出 (鍵ファイルの場合は None)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	kdf: Option<KdfParameters>,
+	/// nonce (BASE64)
+	nonce: String,
+	/// 暗号文と認証タグ (BASE64)
+	ciphertext: String,
+}
+
+///
+/// BASE64 を復号します。
+///
+fn decode_base64(s: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+	use base64::Engine;
+
+	return Ok(base64::engine::general_purpose::STANDARD.decode(s.trim())?);
+}
+
+impl EncryptionKey {
+	///
+	/// 鍵ファイルが指定されていればそれを、無ければ環境変数 `R_GOOGLE_OAUTH2_PASSPHRASE` のパスフレーズを使用します。
+	///
+	/// # Arguments
+	/// * `key_file` - 鍵ファイルのパス
+	///
+	pub fn resolve(key_file: Option<&std::path::Path>) -> Result<Self, Box<dyn std::error::Error>> {
+		if let Some(path) = key_file {
+			return Ok(EncryptionKey::KeyFile(path.to_path_buf()));
+		}
+		return match std::env::var(PASSPHRASE_ENV) {
+			Ok(passphrase) if !passphrase.is_empty() => Ok(EncryptionKey::Passphrase(passphrase)),
+			_ => Err(format!(
+				"--key-file または環境変数 {} で暗号化の鍵を指定してください。",
+				PASSPHRASE_ENV
+			)
+			.into()),
+		};
+	}
+
+	///
+	/// 鍵ファイルから鍵を読み込みます。
+	///
+	fn read_key_file(path: &std::path::Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+		let content = std::fs::read(path)
+			.map_err(|e| format!("鍵ファイルを読み込めません。[{}] 理由: {}", path.display(), e))?;
+		if content.len() == KEY_LENGTH {
+			return Ok(content);
+		}
+		let key = std::str::from_utf8(&content)
+			.ok()
+			.and_then(|text| decode_base64(text).ok())
+			.filter(|key| key.len() == KEY_LENGTH);
+		return key.ok_or_else(|| {
+			format!(
+				"鍵ファイルは {} バイトのバイナリ、または BASE64 のテキストである必要があります。[{}]",
+				KEY_LENGTH,
+				path.display()
+			)
+			.into()
+		});
+	}
+
+	///
+	/// 暗号化の鍵を導出します。
+	///
+	fn derive(&self, kdf: Option<&KdfParameters>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+		return match (self, kdf) {
+			(EncryptionKey::KeyFile(path), None) => Self::read_key_file(path),
+			(EncryptionKey::Passphrase(passphrase), Some(kdf)) => {
+				let params = argon2::Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(KEY_LENGTH))
+					.map_err(|e| format!("Argon2 のパラメーターが正しくありません。理由: {}", e))?;
+				let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+				let mut key = vec![0u8; KEY_LENGTH];
+				argon2
+					.hash_password_into(passphrase.as_bytes(), &decode_base64(&kdf.salt)?, &mut key)
+					.map_err(|e| format!("鍵を導出できません。理由: {}", e))?;
+				Ok(key)
+			}
+			(EncryptionKey::KeyFile(_), Some(_)) => {
+				Err("このファイルはパスフレーズで暗号化されています。".into())
+			}
+			(EncryptionKey::Passphrase(_), None) => {
+				Err("このファイルは鍵ファイルで暗号化されています。".into())
+			}
+		};
+	}
+
+	///
+	/// 新しく暗号化するための鍵の導出のパラメーターを返します。
+	///
+	fn new_kdf_parameters(&self) -> Option<KdfParameters> {
+		return match self {
+			EncryptionKey::KeyFile(_) => None,
+			EncryptionKey::Passphrase(_) => Some(KdfParameters {
+				salt: util::encode_base64(&util::generate_random_u8_array(SALT_LENGTH)),
+				m_cost: argon2::Params::DEFAULT_M_COST,
+				t_cost: argon2::Params::DEFAULT_T_COST,
+				p_cost: argon2::Params::DEFAULT_P_COST,
+			}),
+		};
+	}
+}
+
+///
+/// 暗号化した JSON ファイルの保存先
+///
+/// XChaCha20-Poly1305 で暗号化し、改ざんされていれば読み込みを拒否します。
+/// 鍵はパスフレーズから Argon2id で導出するか、鍵ファイルから読み込みます。
+///
+pub struct EncryptedFileStore {
+	path: std::path::PathBuf,
+	key: EncryptionKey,
+}
+
+impl EncryptedFileStore {
+	///
+	/// 新しいインスタンスを返します。
+	///
+	/// # Arguments
+	/// * `path` - ファイルパス
+	/// * `key` - 暗号化の鍵
+	///
+	pub fn new(path: &std::path::Path, key: EncryptionKey) -> Self {
+		return Self {
+			path: path.to_path_buf(),
+			key,
+		};
+	}
+
+	///
+	/// 暗号化・復号に使う追加の認証データ (形式名とバージョン) を返します。
+	///
+	fn associated_data() -> String {
+		return format!("{}/{}", ENCRYPTED_FILE_FORMAT, ENCRYPTED_FILE_VERSION);
+	}
+}
+
+impl CredentialStore for EncryptedFileStore {
+	fn load(&self) -> Result<Option<TokenData>, Box<dyn std::error::Error>> {
+		use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+
+		if !self.path.is_file() {
+			return Ok(None);
+		}
+		let text = std::fs::read_to_string(&self.path)?;
+		let file: EncryptedFile = serde_json::from_str(&text)
+			.ok()
+			.filter(|f: &EncryptedFile| f.format == ENCRYPTED_FILE_FORMAT)
+			.ok_or_else(|| {
+				format!(
+					"暗号化されたファイルではありません。encrypt コマンドで暗号化してください。[{}]",
+					self.path.display()
+				)
+			})?;
+		if file.version != ENCRYPTED_FILE_VERSION || file.cipher != "xchacha20poly1305" {
+			return Err(format!("対応していない形式です。[{} {}]", file.version, file.cipher).into());
+		}
+
+		let key = self.key.derive(file.kdf.as_ref())?;
+		let cipher = chacha20poly1305::XChaCha20Poly1305::new_from_slice(&key)?;
+		let nonce = chacha20poly1305::XNonce::try_from(decode_base64(&file.nonce)?.as_slice())
+			.map_err(|_| "nonce の長さが正しくありません。")?;
+		let aad = Self::associated_data();
+		let payload = Payload {
+			msg: &decode_base64(&file.ciphertext)?,
+			aad: aad.as_bytes(),
+		};
+		let plaintext = cipher.decrypt(&nonce, payload).map_err(|_| {
+			format!(
+				"復号できません。鍵が違うか、ファイルが改ざんされています。[{}]",
+				self.path.display()
+			)
+		})?;
+
+		let token_data: TokenData = serde_json::from_slice(&plaintext)?;
+		return Ok(Some(token_data));
+	}
+
+	fn save(&self, token_data: &TokenData) -> Result<(), Box<dyn std::error::Error>> {
+		use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+
+		let kdf = self.key.new_kdf_parameters();
+		let key = self.key.derive(kdf.as_ref())?;
+		let cipher = chacha20poly1305::XChaCha20Poly1305::new_from_slice(&key)?;
+		let nonce_bytes = util::generate_random_u8_array(NONCE_LENGTH);
+		let nonce = chacha20poly1305::XNonce::try_from(nonce_bytes.as_slice())
+			.map_err(|_| "nonce の長さが正しくありません。")?;
+		let plaintext = serde_json::to_vec(token_data)?;
+		let aad = Self::associated_data();
+		let payload = Payload {
+			msg: &plaintext,
+			aad: aad.as_bytes(),
+		};
+		let ciphertext = cipher
+			.encrypt(&nonce, payload)
+			.map_err(|_| "暗号化できません。")?;
+
+		let file = EncryptedFile {
+			format: ENCRYPTED_FILE_FORMAT.to_string(),
+			version: ENCRYPTED_FILE_VERSION,
+			cipher: "xchacha20poly1305".to_string(),
+			kdf,
+			nonce: util::encode_base64(&nonce_bytes),
+			ciphertext: util::encode_base64(&ciphertext),
+		};
+		let json = serde_json::to_string_pretty(&file)?;
+		util::write_secret_file(&self.path, json.as_bytes())?;
+		return Ok(());
+	}
+
+	fn delete(&self) -> Result<(), Box<dyn std::error::Error>> {
+		if self.path.exists() {
+			std::fs::remove_file(&self.path)?;
+		}
+		return Ok(());
+	}
+
+	fn lock(&self) -> Result<Option<CredentialLock>, Box<dyn std::error::Error>> {
+		return Ok(Some(CredentialLock::acquire(&self.path)?));
+	}
+}
+
+/// キーリングの項目を識別する属性 (application) の値
+#[cfg(feature = "keyring")]
+const KEYRING_APPLICATION: &str = "r-google-oauth2";
+
+///
+/// Secret Service (D-Bus のキーリング) の保存先
+///
+/// GNOME Keyring や KeePassXC などの Secret Service に、client_id ごとの項目として保存します。
+/// `keyring` 機能を有効にしてビルドした場合のみ使用できます。
+///
+#[cfg(feature = "keyring")]
+pub struct KeyringStore {
+	client_id: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringStore {
+	///
+	/// 新しいインスタンスを返します。
+	///
+	/// # Arguments
+	/// * `client_id` - 項目を識別するクライアント ID
+	///
+	pub fn new(client_id: &str) -> Self {
+		return Self {
+			client_id: client_id.to_string(),
+		};
+	}
+
+	///
+	/// 項目を検索する属性を返します。
+	///
+	fn attributes(&self) -> std::collections::HashMap<&str, &str> {
+		return std::collections::HashMap::from([
+			("application", KEYRING_APPLICATION),
+			("client_id", self.client_id.as_str()),
+		]);
+	}
+
+	///
+	/// Secret Service に接続します。
+	///
+	fn connect<'a>() -> Result<secret_service::blocking::SecretService<'a>, Box<dyn std::error::Error>> {
+		let service = secret_service::blocking::SecretService::connect(secret_service::EncryptionType::Dh)
+			.map_err(|e| format!("キーリング (Secret Service) に接続できません。理由: {}", e))?;
+		return Ok(service);
+	}
+}
+
+#[cfg(feature = "keyring")]
+impl CredentialStore for KeyringStore {
+	fn load(&self) -> Result<Option<TokenData>, Box<dyn std::error::Error>> {
+		let service = Self::connect()?;
+		let items = service.search_items(self.attributes())?;
+		let item = match items.unlocked.first().or(items.locked.first()) {
+			Some(item) => item,
+			None => return Ok(None),
+		};
+		item.ensure_unlocked()
+			.map_err(|e| format!("キーリングのロックを解除できません。理由: {}", e))?;
+		let secret = item.get_secret()?;
+		let token_data: TokenData = serde_json::from_slice(&secret)
+			.map_err(|e| format!("キーリングの資格情報を解析できません。理由: {}", e))?;
+		return Ok(Some(token_data));
+	}
+
+	fn save(&self, token_data: &TokenData) -> Result<(), Box<dyn std::error::Error>> {
+		let service = Self::connect()?;
+		let collection = service.get_default_collection()?;
+		collection
+			.ensure_unlocked()
+			.map_err(|e| format!("キーリングのロックを解除できません。理由: {}", e))?;
+		let json = serde_json::to_vec(token_data)?;
+		let label = format!("{} ({})", KEYRING_APPLICATION, self.client_id);
+		collection.create_item(&label, self.attributes(), &json, true, "application/json")?;
+		return Ok(());
+	}
+
+	fn delete(&self) -> Result<(), Box<dyn std::error::Error>> {
+		let service = Self::connect()?;
+		let items = service.search_items(self.attributes())?;
+		for item in items.unlocked.iter().chain(items.locked.iter()) {
+			item.delete()?;
+		}
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn temp_dir(name: &str) -> std::path::PathBuf {
+		let dir = std::env::temp_dir().join(format!("r-google-oauth2-{}-{}", name, std::process::id()));
+		let _ = std::fs::remove_dir_all(&dir);
+		return dir;
+	}
+
+	fn token_data() -> TokenData {
+		return serde_json::from_str(r#"{"access_token":"ya29.secret","refresh_token":"1//secret"}"#)
+			.unwrap();
+	}
+
+	fn passphrase_store(path: &std::path::Path, passphrase: &str) -> EncryptedFileStore {
+		return EncryptedFileStore::new(path, EncryptionKey::Passphrase(passphrase.to_string()));
+	}
+
+	#[test]
+	fn encrypted_file_store_round_trips_with_a_passphrase() {
+		let dir = temp_dir("encrypted-passphrase");
+		let path = dir.join("credentials.enc.json");
+		let store = passphrase_store(&path, "correct horse");
+
+		assert!(store.load().unwrap().is_none());
+		store.save(&token_data()).unwrap();
+
+		// 平文のトークンはファイルに書き込まれません。
+		let text = std::fs::read_to_string(&path).unwrap();
+		assert!(!text.contains("ya29.secret"));
+		assert!(!text.contains("1//secret"));
+
+		let loaded = store.load().unwrap().unwrap();
+		assert_eq!(loaded.access_token, "ya29.secret");
+		assert_eq!(loaded.refresh_token(), Some("1//secret"));
+
+		store.delete().unwrap();
+		assert!(!path.exists());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn encrypted_file_store_round_trips_with_a_key_file() {
+		let dir = temp_dir("encrypted-key-file");
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("credentials.enc.json");
+		let key_path = dir.join("key");
+		std::fs::write(&key_path, util::encode_base64(&[7u8; KEY_LENGTH])).unwrap();
+		let store = EncryptedFileStore::new(&path, EncryptionKey::KeyFile(key_path.clone()));
+
+		store.save(&token_data()).unwrap();
+		assert_eq!(store.load().unwrap().unwrap().access_token, "ya29.secret");
+
+		// 別の鍵ファイルやパスフレーズでは復号できません。
+		std::fs::write(&key_path, [8u8; KEY_LENGTH]).unwrap();
+		let error = store.load().unwrap_err().to_string();
+		assert!(error.contains("復号できません"), "{}", error);
+		let error = passphrase_store(&path, "correct horse")
+			.load()
+			.unwrap_err()
+			.to_string();
+		assert!(error.contains("鍵ファイルで暗号化されています"), "{}", error);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn encrypted_file_store_rejects_a_wrong_passphrase() {
+		let dir = temp_dir("encrypted-wrong-passphrase");
+		let path = dir.join("credentials.enc.json");
+		passphrase_store(&path, "correct horse")
+			.save(&token_data())
+			.unwrap();
+
+		let error = passphrase_store(&path, "wrong horse")
+			.load()
+			.unwrap_err()
+			.to_string();
+		assert!(error.contains("復号できません"), "{}", error);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn encrypted_file_store_rejects_tampered_files() {
+		let dir = temp_dir("encrypted-tampered");
+		let path = dir.join("credentials.enc.json");
+		let store = passphrase_store(&path, "correct horse");
+		store.save(&token_data()).unwrap();
+		let original = std::fs::read_to_string(&path).unwrap();
+
+		// 暗号文を 1 バイト書き換えます。
+		let mut file: EncryptedFile = serde_json::from_str(&original).unwrap();
+		let mut ciphertext = decode_base64(&file.ciphertext).unwrap();
+		ciphertext[0] ^= 0x01;
+		file.ciphertext = util::encode_base64(&ciphertext);
+		std::fs::write(&path, serde_json::to_string(&file).unwrap()).unwrap();
+		let error = store.load().unwrap_err().to_string();
+		assert!(error.contains("改ざん"), "{}", error);
+
+		// 追加の認証データ (バージョン) を書き換えても読み込みません。
+		let mut file: EncryptedFile = serde_json::from_str(&original).unwrap();
+		file.version = ENCRYPTED_FILE_VERSION + 1;
+		std::fs::write(&path, serde_json::to_string(&file).unwrap()).unwrap();
+		assert!(store.load().is_err());
+
+		// 暗号化されていない JSON ファイルは拒否します。
+		std::fs::write(&path, r#"{"access_token":"ya29.secret"}"#).unwrap();
+		let error = store.load().unwrap_err().to_string();
+		assert!(error.contains("encrypt コマンド"), "{}", error);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn encrypted_file_store_writes_the_file_with_mode_0600() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = temp_dir("encrypted-mode");
+		let path = dir.join("credentials.enc.json");
+		passphrase_store(&path, "correct horse")
+			.save(&token_data())
+			.unwrap();
+
+		let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+		assert_eq!(mode & 0o777, 0o600);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn encryption_key_resolves_the_key_file_before_the_environment_variable() {
+		let key_path = std::path::Path::new("/path/to/key");
+
+		// 環境変数に触れるテストはこれだけにしておきます。
+		std::env::set_var(PASSPHRASE_ENV, "from-env");
+		let key_file = EncryptionKey::resolve(Some(key_path));
+		let passphrase = EncryptionKey::resolve(None);
+		std::env::set_var(PASSPHRASE_ENV, "");
+		let empty = EncryptionKey::resolve(None);
+		std::env::remove_var(PASSPHRASE_ENV);
+		let missing = EncryptionKey::resolve(None);
+
+		assert!(matches!(key_file.unwrap(), EncryptionKey::KeyFile(path) if path == key_path));
+		assert!(matches!(passphrase.unwrap(), EncryptionKey::Passphrase(p) if p == "from-env"));
+		assert!(empty.unwrap_err().to_string().contains(PASSPHRASE_ENV));
+		assert!(missing.unwrap_err().to_string().contains(PASSPHRASE_ENV));
+	}
+
+	#[test]
+	fn encryption_key_reads_binary_and_base64_key_files() {
+		let dir = temp_dir("key-file");
+		std::fs::create_dir_all(&dir).unwrap();
+		let key_path = dir.join("key");
+
+		std::fs::write(&key_path, [1u8; KEY_LENGTH]).unwrap();
+		assert_eq!(
+			EncryptionKey::read_key_file(&key_path).unwrap(),
+			vec![1u8; KEY_LENGTH]
+		);
+
+		std::fs::write(
+			&key_path,
+			format!("{}\n", util::encode_base64(&[2u8; KEY_LENGTH])),
+		)
+		.unwrap();
+		assert_eq!(
+			EncryptionKey::read_key_file(&key_path).unwrap(),
+			vec![2u8; KEY_LENGTH]
+		);
+
+		std::fs::write(&key_path, "too short").unwrap();
+		let error = EncryptionKey::read_key_file(&key_path).unwrap_err().to_string();
+		assert!(error.contains("バイトのバイナリ"), "{}", error);
+
+		assert!(EncryptionKey::read_key_file(&dir.join("missing")).is_err());
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	/// Secret Service (gnome-keyring など) が動いているセッションで、
+	/// `cargo test --features keyring -- --ignored` として実行します。
+	#[cfg(feature = "keyring")]
+	#[test]
+	#[ignore]
+	fn keyring_store_saves_loads_and_deletes_a_single_item() {
+		let client_id = format!("test-{}.apps.googleusercontent.com", std::process::id());
+		let store = KeyringStore::new(&client_id);
+		let first: TokenData =
+			serde_json::from_str(r#"{"access_token":"at1","refresh_token":"rt"}"#).unwrap();
+		let second: TokenData =
+			serde_json::from_str(r#"{"access_token":"at2","refresh_token":"rt"}"#).unwrap();
+
+		store.save(&first).unwrap();
+		store.save(&second).unwrap();
+
+		// 同じ属性の項目は、追加されずに置き換えられます。
+		let service = KeyringStore::connect().unwrap();
+		let items = service.search_items(store.attributes()).unwrap();
+		assert_eq!(items.unlocked.len() + items.locked.len(), 1);
+		assert_eq!(store.load().unwrap().unwrap().access_token, "at2");
+
+		store.delete().unwrap();
+		assert!(store.load().unwrap().is_none());
+	}
+}
@@ -0,0 +1,335 @@
+//!
+//! HTTP 通信の抽象化
+//!
+//! `GoogleOAuth2` は、すべての HTTP 通信を `HttpTransport` を介して行います。
+//! 既定では reqwest を使用し、テストでは `MockTransport` に差し替えることができます。
+
+use crate::util;
+
+///
+/// HTTP リクエスト
+///
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+	/// "GET", "POST" など
+	pub method: String,
+	/// URL
+	pub url: String,
+	/// ヘッダー
+	pub headers: Vec<(String, String)>,
+	/// 本文
+	pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+	///
+	/// GET リクエストを作成します。
+	///
+	pub fn get(url: &str) -> Self {
+		return Self {
+			method: "GET".to_string(),
+			url: url.to_string(),
+			headers: vec![],
+			body: vec![],
+		};
+	}
+
+	///
+	/// POST リクエスト (application/x-www-form-urlencoded) を作成します。
+	///
+	/// # Arguments
+	/// * `url` - URL
+	/// * `params` - フォームのパラメーター
+	///
+	pub fn post_form(url: &str, params: &[(&str, &str)]) -> Self {
+		let body: Vec<String> = params
+			.iter()
+			.map(|(key, value)| format!("{}={}", util::urlencode(key), util::urlencode(value)))
+			.collect();
+		return Self {
+			method: "POST".to_string(),
+			url: url.to_string(),
+			headers: vec![(
+				"Content-Type".to_string(),
+				"application/x-www-form-urlencoded".to_string(),
+			)],
+			body: body.join("&").into_bytes(),
+		};
+	}
+
+	///
+	/// ヘッダーを追加します。
+	///
+	pub fn with_header(mut self, name: &str, value: &str) -> Self {
+		self.headers.push((name.to_string(), value.to_string()));
+		return self;
+	}
+
+	///
+	/// Authorization: Bearer ヘッダーを設定します。既存の Authorization ヘッダーは置き換えます。
+	///
+	pub fn with_bearer(mut self, access_token: &str) -> Self {
+		self.headers
+			.retain(|(name, _)| !name.eq_ignore_ascii_case("Authorization"));
+		return self.with_header("Authorization", &format!("Bearer {}", access_token));
+	}
+
+	///
+	/// JSON を本文とする POST リクエストを作成します。
+	///
+	pub fn post_json(url: &str, body: &serde_json::Value) -> Self {
+		return Self {
+			method: "POST".to_string(),
+			url: url.to_string(),
+			headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+			body: body.to_string().into_bytes(),
+		};
+	}
+}
+
+///
+/// HTTP レスポンス
+///
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+	/// ステータスコード
+	pub status: u16,
+	/// ヘッダー
+	pub headers: Vec<(String, String)>,
+	/// 本文
+	pub body: String,
+}
+
+impl HttpResponse {
+	///
+	/// ヘッダーの値を返します。(大文字と小文字を区別しません)
+	///
+	pub fn header(&self, name: &str) -> Option<&str> {
+		return self
+			.headers
+			.iter()
+			.find(|(k, _)| k.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.as_str());
+	}
+
+	///
+	/// 2xx かどうかを返します。
+	///
+	pub fn is_success(&self) -> bool {
+		return (200..300).contains(&self.status);
+	}
+}
+
+///
+/// HTTP 通信を行うもの
+///
+pub trait HttpTransport: Send + Sync {
+	///
+	/// リクエストを送信して、レスポンスを返します。
+	///
+	/// 4xx や 5xx もエラーではなくレスポンスとして返します。
+	///
+	fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Box<dyn std::error::Error>>;
+}
+
+///
+/// HTTP クライアントの設定
+///
+#[derive(Debug, Clone)]
+pub struct HttpClientOptions {
+	/// 接続のタイムアウト
+	pub connect_timeout: std::time::Duration,
+	/// リクエスト全体 (レスポンスの読み込みを含む) のタイムアウト
+	pub timeout: std::time::Duration,
+	/// User-Agent
+	pub user_agent: String,
+	/// プロキシ。指定しない場合は環境変数 HTTPS_PROXY, HTTP_PROXY, ALL_PROXY, NO_PROXY に従います。
+	pub proxy: Option<String>,
+	/// 追加で信頼するルート証明書 (PEM または DER) のパス
+	pub extra_root_certificates: Vec<std::path::PathBuf>,
+}
+
+impl Default for HttpClientOptions {
+	fn default() -> Self {
+		return Self {
+			connect_timeout: std::time::Duration::from_secs(10),
+			timeout: std::time::Duration::from_secs(30),
+			user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+			proxy: None,
+			extra_root_certificates: vec![],
+		};
+	}
+}
+
+///
+/// 証明書ファイルを読み込みます。PEM の場合は、複数の証明書を含むことができます。
+///
+/// # Arguments
+/// * `path` - ファイルパス
+///
+fn load_certificates(
+	path: &std::path::Path,
+) -> Result<Vec<reqwest::Certificate>, Box<dyn std::error::Error>> {
+	let content = std::fs::read(path).map_err(|e| format!("{} を開けません。理由: {}", path.display(), e))?;
+
+	const END_MARKER: &str = "-----END CERTIFICATE-----";
+	let text = String::from_utf8_lossy(&content);
+	if !text.contains(END_MARKER) {
+		return Ok(vec![reqwest::Certificate::from_der(&content)?]);
+	}
+
+	let mut certificates = vec![];
+	for block in text.split_inclusive(END_MARKER) {
+		if !block.contains(END_MARKER) {
+			continue;
+		}
+		certificates.push(reqwest::Certificate::from_pem(block.trim_start().as_bytes())?);
+	}
+	return Ok(certificates);
+}
+
+///
+/// reqwest による HTTP 通信
+///
+/// 接続プールと TLS セッションを再利用するため、ひとつのクライアントを使い続けます。
+///
+pub struct ReqwestTransport {
+	client: reqwest::blocking::Client,
+}
+
+impl ReqwestTransport {
+	///
+	/// 既定の設定で、新しいインスタンスを返します。
+	///
+	pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+		return Self::with_options(&HttpClientOptions::default());
+	}
+
+	///
+	/// 設定を指定して、新しいインスタンスを返します。
+	///
+	pub fn with_options(options: &HttpClientOptions) -> Result<Self, Box<dyn std::error::Error>> {
+		let mut builder = reqwest::blocking::Client::builder()
+			.connect_timeout(options.connect_timeout)
+			.timeout(options.timeout)
+			.user_agent(options.user_agent.as_str());
+
+		if let Some(proxy) = &options.proxy {
+			let proxy = reqwest::Proxy::all(proxy.as_str())?.no_proxy(reqwest::NoProxy::from_env());
+			builder = builder.proxy(proxy);
+		}
+
+		for path in &options.extra_root_certificates {
+			for certificate in load_certificates(path)? {
+				builder = builder.add_root_certificate(certificate);
+			}
+		}
+
+		let client = builder.build()?;
+		return Ok(Self { client });
+	}
+}
+
+impl HttpTransport for ReqwestTransport {
+	fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		let method = reqwest::Method::from_bytes(request.method.as_bytes())?;
+		let mut builder = self.client.request(method, &request.url);
+		for (name, value) in &request.headers {
+			builder = builder.header(name, value);
+		}
+		if !request.body.is_empty() {
+			builder = builder.body(request.body.clone());
+		}
+		let response = builder.send()?;
+
+		let status = response.status().as_u16();
+		let headers = response
+			.headers()
+			.iter()
+			.map(|(name, value)| (name.to_string(), value.to_str().unwrap_or_default().to_string()))
+			.collect();
+		let body = response.text()?;
+
+		return Ok(HttpResponse {
+			status,
+			headers,
+			body,
+		});
+	}
+}
+
+///
+/// テスト用の HTTP 通信
+///
+/// 登録された順にレスポンスを返し、受け取ったリクエストを記録します。
+///
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockTransport {
+	responses: std::sync::Mutex<std::collections::VecDeque<Result<HttpResponse, String>>>,
+	requests: std::sync::Mutex<Vec<HttpRequest>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+	///
+	/// 新しいインスタンスを返します。
+	///
+	pub fn new() -> Self {
+		return Self::default();
+	}
+
+	///
+	/// 次に返すレスポンスを登録します。
+	///
+	pub fn push_response(&self, status: u16, body: &str) {
+		let response = HttpResponse {
+			status,
+			headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+			body: body.to_string(),
+		};
+		self.responses.lock().unwrap().push_back(Ok(response));
+	}
+
+	///
+	/// 次に返すエラー (接続の失敗など) を登録します。
+	///
+	pub fn push_error(&self, message: &str) {
+		self.responses.lock().unwrap().push_back(Err(message.to_string()));
+	}
+
+	///
+	/// これまでに受け取ったリクエストを返します。
+	///
+	pub fn requests(&self) -> Vec<HttpRequest> {
+		return self.requests.lock().unwrap().clone();
+	}
+}
+
+#[cfg(test)]
+impl HttpTransport for MockTransport {
+	fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		self.requests.lock().unwrap().push(request.clone());
+		let next = self.responses.lock().unwrap().pop_front();
+		return match next {
+			Some(Ok(response)) => Ok(response),
+			Some(Err(message)) => Err(message.into()),
+			None => Err(format!(
+				"レスポンスが登録されていません。[{} {}]",
+				request.method, request.url
+			)
+			.into()),
+		};
+	}
+}
+
+///
+/// 共有された HTTP 通信
+///
+/// `Arc<MockTransport>` などを `GoogleOAuth2` に渡した後も、呼び出し側で参照できるようにします。
+///
+impl<T: HttpTransport + ?Sized> HttpTransport for std::sync::Arc<T> {
+	fn send(&self, request: &HttpRequest) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+		return (**self).send(request);
+	}
+}
@@ -13,92 +13,291 @@ pub fn get_current_timestamp() -> String {
 	timestamp
 }
 
-#[macro_export]
-macro_rules! info {
-    ($($arg:tt)*) => {
-		let line = format!($($arg)*);
-		let current_timestamp = crate::util::get_current_timestamp();
-		let pid = std::process::id();
-		// let _ = std::format_args!("{}", line);
-        println!("{} ({}) [info] {}", current_timestamp, pid, line);
-    };
+///
+/// クエリパラメーター
+///
+/// 同じキーが複数回現れることがあるため、出現順に保持します。
+///
+#[derive(Debug, Default, Clone)]
+pub struct QueryParameters {
+	pairs: Vec<(String, String)>,
 }
 
-#[macro_export]
-macro_rules! error {
-    ($($arg:tt)*) => {
-		let line = format!($($arg)*);
-		let current_timestamp = crate::util::get_current_timestamp();
-		let pid = std::process::id();
-		// let _ = std::format_args!("{}", line);
-        println!("{} ({}) [error] {}", current_timestamp, pid, line);
-    };
+impl serde::Serialize for QueryParameters {
+	/// ログに出力できるよう、JSON オブジェクトとして (重複するキーもそのまま) 書き出します。
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		use serde::ser::SerializeMap;
+
+		let mut map = serializer.serialize_map(Some(self.pairs.len()))?;
+		for (key, value) in &self.pairs {
+			map.serialize_entry(key, value)?;
+		}
+		return map.end();
+	}
+}
+
+impl QueryParameters {
+	///
+	/// application/x-www-form-urlencoded 形式の文字列を解析します。
+	///
+	/// "=" を持たないキーは空の値として扱い、空の要素は無視します。
+	///
+	/// # Arguments
+	/// * `query` - "a=1&b=2" の形式の文字列 ("?" を含まない)
+	///
+	pub fn parse(query: &str) -> Result<Self, Box<dyn std::error::Error>> {
+		let mut result = Self::default();
+		result.append(query)?;
+		return Ok(result);
+	}
+
+	///
+	/// application/x-www-form-urlencoded 形式の文字列を解析して追加します。
+	///
+	pub fn append(&mut self, query: &str) -> Result<(), Box<dyn std::error::Error>> {
+		for pair in query.split('&') {
+			if pair.is_empty() {
+				continue;
+			}
+			let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+			self.pairs.push((form_urldecode(key)?, form_urldecode(value)?));
+		}
+		return Ok(());
+	}
+
+	///
+	/// キーに対応する最初の値を返します。
+	///
+	pub fn get(&self, key: &str) -> Option<&str> {
+		return self.pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+	}
+
+	///
+	/// キーに対応するすべての値を返します。
+	///
+	pub fn get_all(&self, key: &str) -> Vec<&str> {
+		return self
+			.pairs
+			.iter()
+			.filter(|(k, _)| k == key)
+			.map(|(_, v)| v.as_str())
+			.collect();
+	}
+
+	///
+	/// キーが含まれるかどうかを返します。
+	///
+	pub fn contains_key(&self, key: &str) -> bool {
+		return self.pairs.iter().any(|(k, _)| k == key);
+	}
 }
 
 ///
-/// URL クエリストリングを解析します。
+/// URL クエリストリングを解析します。フラグメント ("#" 以降) は無視します。
 ///
-fn split_querystring(url: &str) -> std::collections::HashMap<String, String> {
-	if !url.contains("?") {
-		return std::collections::HashMap::new();
+pub fn split_querystring(url: &str) -> Result<QueryParameters, Box<dyn std::error::Error>> {
+	let url = url.split_once('#').map(|(u, _)| u).unwrap_or(url);
+	let querystring = match url.split_once('?') {
+		Some((_, querystring)) => querystring,
+		None => return Ok(QueryParameters::default()),
+	};
+	return QueryParameters::parse(querystring);
+}
+
+///
+/// 受信した HTTP リクエスト
+///
+#[derive(Debug, Default)]
+pub struct IncomingRequest {
+	/// "GET", "POST" など
+	pub method: String,
+	/// "/?state=..." など
+	pub target: String,
+	/// ヘッダー
+	pub headers: Vec<(String, String)>,
+	/// 本文
+	pub body: Vec<u8>,
+}
+
+impl IncomingRequest {
+	///
+	/// ヘッダーの値を返します。(大文字と小文字を区別しません)
+	///
+	pub fn header(&self, name: &str) -> Option<&str> {
+		return self
+			.headers
+			.iter()
+			.find(|(k, _)| k.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.as_str());
 	}
-	let (_, querystring) = url.split_once("?").unwrap();
+}
+
+/// 受け付けるリクエスト本文の大きさの上限
+const MAX_REQUEST_BODY_SIZE: usize = 64 * 1024;
+
+/// 受け付けるリクエスト行とヘッダーを合わせた大きさの上限
+const MAX_REQUEST_HEADER_SIZE: usize = 16 * 1024;
 
-	let mut query = std::collections::HashMap::new();
-	for pair in querystring.split("&") {
-		let mut iter = pair.split("=");
-		let key = iter.next().unwrap();
-		let value = iter.next().unwrap();
-		query.insert(key.to_string(), urldecode(value));
+///
+/// リクエスト行、またはヘッダーの 1 行を、残りの上限を超えないように読み込みます。
+///
+/// # Arguments
+/// * `reader` - 接続
+/// * `remaining` - リクエスト行とヘッダーに残っている大きさ
+///
+fn read_header_line(
+	reader: &mut dyn std::io::BufRead,
+	remaining: &mut usize,
+) -> Result<String, Box<dyn std::error::Error>> {
+	use std::io::{BufRead, Read};
+
+	let mut line = String::new();
+	let size = reader.take(*remaining as u64).read_line(&mut line)?;
+	*remaining -= size;
+	if *remaining == 0 && !line.ends_with('\n') {
+		return Err("リクエストヘッダーが大きすぎます。".into());
 	}
-	return query;
+	return Ok(line);
 }
 
 ///
-/// "GET /?state=... HTTP/1.1" から URL を抽出します。
+/// HTTP リクエストを読み込みます。
 ///
-fn get_request_path(unknown: &str) -> String {
-	if !unknown.starts_with("GET /") {
-		return String::new();
+/// # Arguments
+/// * `reader` - 接続
+///
+pub fn read_http_request(
+	reader: &mut dyn std::io::BufRead,
+) -> Result<IncomingRequest, Box<dyn std::error::Error>> {
+	let mut request = IncomingRequest::default();
+	let mut remaining = MAX_REQUEST_HEADER_SIZE;
+
+	// "GET /?state=... HTTP/1.1"
+	let line = read_header_line(reader, &mut remaining)?;
+	let mut items = line.split_whitespace();
+	request.method = items.next().unwrap_or_default().to_string();
+	request.target = items.next().unwrap_or_default().to_string();
+	if request.method.is_empty() || request.target.is_empty() {
+		return Err(format!("不正なリクエストです。[{}]", line.trim_end()).into());
 	}
-	let mut items = unknown.split(" ");
-	let url = items.nth(1).unwrap();
-	return url.to_string();
+
+	// ヘッダー
+	loop {
+		let line = read_header_line(reader, &mut remaining)?;
+		if line.is_empty() {
+			break;
+		}
+		let line = line.trim_end();
+		if line.is_empty() {
+			break;
+		}
+		if let Some((name, value)) = line.split_once(':') {
+			request
+				.headers
+				.push((name.trim().to_string(), value.trim().to_string()));
+		}
+	}
+
+	// 本文
+	let length: usize = request.header("Content-Length").unwrap_or("0").parse()?;
+	if MAX_REQUEST_BODY_SIZE < length {
+		return Err("リクエスト本文が大きすぎます。".into());
+	}
+	let mut body = vec![0u8; length];
+	reader.read_exact(&mut body)?;
+	request.body = body;
+
+	return Ok(request);
 }
 
 ///
 /// HTTP リクエストを解析します。
 ///
-pub fn diagnose_http_request(http_request: &Vec<String>) -> std::collections::HashMap<String, String> {
-	for line in http_request {
-		let url = get_request_path(line);
-		if url == "" {
-			continue;
+/// GET の場合はクエリストリングを、POST (response_mode=form_post) の場合は
+/// application/x-www-form-urlencoded の本文を解析します。
+///
+pub fn diagnose_http_request(
+	request: &IncomingRequest,
+) -> Result<QueryParameters, Box<dyn std::error::Error>> {
+	let mut query = split_querystring(&request.target)?;
+	if request.method == "POST" {
+		let content_type = request.header("Content-Type").unwrap_or_default();
+		if !content_type.starts_with("application/x-www-form-urlencoded") {
+			return Err(format!("対応していない Content-Type です。[{}]", content_type).into());
 		}
-		return split_querystring(&url);
+		let body = std::str::from_utf8(&request.body)?;
+		query.append(body)?;
 	}
-	return std::collections::HashMap::new();
+	return Ok(query);
 }
 
 ///
-/// URL デコード
+/// URL デコード (RFC 3986)
 ///
-fn urldecode(s: &str) -> String {
-	let mut result = String::new();
+/// "%XX" をバイトとして復元し、UTF-8 として解釈します。不正なエスケープや UTF-8 として不正なバイト列はエラーになります。
+///
+pub fn urldecode(s: &str) -> Result<String, Box<dyn std::error::Error>> {
+	return percent_decode(s, false);
+}
+
+///
+/// URL デコード (application/x-www-form-urlencoded)
+///
+/// "+" を空白として扱います。
+///
+pub fn form_urldecode(s: &str) -> Result<String, Box<dyn std::error::Error>> {
+	return percent_decode(s, true);
+}
+
+///
+/// パーセントエンコーディングされた文字列を復元します。
+///
+/// # Arguments
+/// * `s` - 文字列
+/// * `plus_as_space` - "+" を空白として扱うかどうか
+///
+fn percent_decode(s: &str, plus_as_space: bool) -> Result<String, Box<dyn std::error::Error>> {
+	let bytes = s.as_bytes();
+	let mut buffer: Vec<u8> = Vec::with_capacity(bytes.len());
 	let mut i = 0;
-	while i < s.len() {
-		let c = s.chars().nth(i).unwrap();
-		if c == '%' {
-			let hex = &s[i + 1..i + 3];
-			let n = u8::from_str_radix(hex, 16).unwrap();
-			result.push(n as char);
-			i += 3;
-		} else {
-			result.push(c);
-			i += 1;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'%' => {
+				// from_str_radix は先頭の "+" を受け付けるため、2 文字とも 16 進数であることを先に確認します。
+				let hex = bytes
+					.get(i + 1..i + 3)
+					.filter(|h| h.iter().all(|b| b.is_ascii_hexdigit()))
+					.and_then(|h| std::str::from_utf8(h).ok());
+				let n = hex.and_then(|h| u8::from_str_radix(h, 16).ok());
+				if n.is_none() {
+					return Err(format!("不正なエスケープです。(位置: {})", i).into());
+				}
+				buffer.push(n.unwrap());
+				i += 3;
+			}
+			b'+' if plus_as_space => {
+				buffer.push(b' ');
+				i += 1;
+			}
+			b => {
+				buffer.push(b);
+				i += 1;
+			}
 		}
 	}
-	result
+	let result = String::from_utf8(buffer).map_err(|_| "UTF-8 として不正なバイト列です。")?;
+	return Ok(result);
+}
+
+///
+/// ブラウザーを開けない環境 (SSH 接続、ディスプレイの無い Linux など) かどうかを返します。
+///
+pub fn is_headless() -> bool {
+	let has_env = |name: &str| std::env::var_os(name).is_some_and(|v| !v.is_empty());
+	if cfg!(any(target_os = "windows", target_os = "macos")) {
+		return has_env("SSH_CONNECTION") || has_env("SSH_TTY");
+	}
+	return !has_env("DISPLAY") && !has_env("WAYLAND_DISPLAY");
 }
 
 ///
@@ -130,21 +329,38 @@ fn try_bind_tcp_port(port: u16) -> Result<bool, Box<dyn std::error::Error>> {
 /// ブラウザーを開きます。
 ///
 pub fn open_browser(url: &str) -> Result<(), Box<dyn std::error::Error>> {
-	info!("OPEN> {}", url);
+	log::info!("OPEN> {}", url);
 	open::that(url)?;
 	return Ok(());
 }
 
 ///
-/// URL エンコーディング
+/// ループバックのリダイレクト URI (`http://localhost:PORT` または `http://127.0.0.1:PORT`) のポートを返します。
+///
+/// ポートが明示されていない URI や、ループバック以外の URI では None を返します。
+///
+pub fn loopback_port(uri: &str) -> Option<u16> {
+	let authority = uri.strip_prefix("http://")?;
+	let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+	let (host, port) = authority.rsplit_once(':')?;
+	if !host.eq_ignore_ascii_case("localhost") && host != "127.0.0.1" {
+		return None;
+	}
+	return port.parse().ok().filter(|port| *port != 0);
+}
+
+///
+/// URL エンコーディング (RFC 3986)
+///
+/// 非予約文字 (英数字と "-", ".", "_", "~") 以外を、UTF-8 のバイトごとに "%XX" に変換します。
 ///
 pub fn urlencode(s: &str) -> String {
 	let mut result = String::new();
-	for c in s.chars() {
-		if c.is_ascii_alphanumeric() {
-			result.push(c);
+	for b in s.bytes() {
+		if b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_' || b == b'~' {
+			result.push(b as char);
 		} else {
-			result.push_str(&format!("%{:02X}", c as u8));
+			result.push_str(&format!("%{:02X}", b));
 		}
 	}
 	return result;
@@ -157,12 +373,12 @@ pub fn urlencode(s: &str) -> String {
 fn build_query_string(params: &std::collections::HashMap<&str, &str>) -> String {
 	let mut query = String::new();
 	for (key, value) in params {
-		if query == "" {
+		if query.is_empty() {
 			query.push('?');
 		} else {
 			query.push('&');
 		}
-		query.push_str(key);
+		query.push_str(&urlencode(key));
 		query.push('=');
 		query.push_str(&urlencode(value));
 	}
@@ -203,6 +419,17 @@ pub fn create_sha256b_hash(s: &str) -> Vec<u8> {
 	return result.as_slice().to_vec();
 }
 
+///
+/// トークンを識別するための指紋 (SHA256 の先頭 8 バイトの 16 進数)
+///
+/// トークンそのものを残さずに、記録やログで同じトークンかどうかを見分けるために使用します。
+///
+pub fn token_fingerprint(token: &str) -> String {
+	let hash = create_sha256b_hash(token);
+	let hex: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+	return format!("sha256:{}", hex);
+}
+
 ///
 /// code_verifier >> code_challenge
 ///
@@ -222,6 +449,16 @@ pub fn encode_base64(buffer: &[u8]) -> String {
 	return result;
 }
 
+///
+/// BASE64URL デコーディング (パディングの有無を問いません)
+///
+pub fn decode_base64url(s: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+	use base64::Engine;
+
+	let result = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(s.trim_end_matches('='))?;
+	return Ok(result);
+}
+
 ///
 /// ランダムな u8 バイト配列を生成します。
 ///
@@ -255,6 +492,12 @@ impl MapHelper for std::collections::HashMap<String, String> {
 	}
 }
 
+impl MapHelper for QueryParameters {
+	fn get_string(&self, key: &str) -> String {
+		return self.get(key).unwrap_or_default().to_string();
+	}
+}
+
 ///
 /// 簡易的なストップウォッチ
 ///
@@ -275,25 +518,245 @@ impl SimpleStopWatch {
 	}
 }
 
+impl Default for SimpleStopWatch {
+	fn default() -> Self {
+		return Self::new();
+	}
+}
+
 ///
-/// POST リクエストを送信します。(フォーム送信型)
+/// 秘密情報を含むファイルを書き込みます。
 ///
-pub fn http_post(
-	url: &str,
-	params: &std::collections::HashMap<&str, &str>,
-) -> Result<String, Box<dyn std::error::Error>> {
-	let client = reqwest::blocking::Client::new();
-	let response = client.post(url).form(params).send()?;
-	let text = response.text()?;
-	return Ok(text);
+/// 親ディレクトリが無ければ作成し、UNIX 系 OS では所有者のみが読み書きできる権限 (0600) にします。
+/// 同じディレクトリの一時ファイルに書き込んでから置き換えるため、途中で失敗しても元の内容は壊れません。
+///
+/// # Arguments
+/// * `path` - ファイルパス
+/// * `content` - 内容
+///
+pub fn write_secret_file(path: &std::path::Path, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+	use std::io::Write;
+
+	let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+	if let Some(parent) = parent {
+		std::fs::create_dir_all(parent)?;
+	}
+	let file_name = path.file_name().ok_or("ファイル名がありません。")?;
+	let temp_name = format!(
+		".{}.{}.{}.tmp",
+		file_name.to_string_lossy(),
+		std::process::id(),
+		generate_random_string(6)
+	);
+	let temp_path = parent.unwrap_or(std::path::Path::new(".")).join(temp_name);
+
+	let mut options = std::fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+		let mut file = options.open(&temp_path)?;
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+		}
+		file.write_all(content)?;
+		file.sync_all()?;
+		std::fs::rename(&temp_path, path)?;
+		return Ok(());
+	})();
+	if result.is_err() {
+		let _ = std::fs::remove_file(&temp_path);
+	}
+	result?;
+
+	// 置き換えを確実に記録するため、ディレクトリも同期します。(失敗しても内容は書き込まれています)
+	#[cfg(unix)]
+	if let Ok(dir) = std::fs::File::open(parent.unwrap_or(std::path::Path::new("."))) {
+		let _ = dir.sync_all();
+	}
+
+	return Ok(());
+}
+
+///
+/// 文字列または数値を i64 として読み込みます。(tokeninfo は数値を文字列で返します)
+///
+pub fn deserialize_string_or_i64<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	use serde::de::Error;
+	use serde::Deserialize;
+
+	let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+	return match value {
+		None | Some(serde_json::Value::Null) => Ok(None),
+		Some(serde_json::Value::Number(n)) => n
+			.as_i64()
+			.map(Some)
+			.ok_or_else(|| D::Error::custom(format!("整数ではありません。[{}]", n))),
+		Some(serde_json::Value::String(s)) => s
+			.trim()
+			.parse()
+			.map(Some)
+			.map_err(|_| D::Error::custom(format!("整数ではありません。[{}]", s))),
+		Some(other) => Err(D::Error::custom(format!("整数ではありません。[{}]", other))),
+	};
 }
 
 ///
-/// GET リクエストを送信します。
+/// 文字列または真偽値を bool として読み込みます。(tokeninfo は真偽値を文字列で返します)
 ///
-pub fn http_get(url: &str) -> Result<String, Box<dyn std::error::Error>> {
-	let client = reqwest::blocking::Client::new();
-	let response = client.get(url).send()?;
-	let text = response.text()?;
-	return Ok(text);
+pub fn deserialize_string_or_bool<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	use serde::de::Error;
+	use serde::Deserialize;
+
+	let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+	return match value {
+		None | Some(serde_json::Value::Null) => Ok(None),
+		Some(serde_json::Value::Bool(b)) => Ok(Some(b)),
+		Some(serde_json::Value::String(s)) => match s.trim() {
+			"true" => Ok(Some(true)),
+			"false" => Ok(Some(false)),
+			_ => Err(D::Error::custom(format!("真偽値ではありません。[{}]", s))),
+		},
+		Some(other) => Err(D::Error::custom(format!("真偽値ではありません。[{}]", other))),
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn urlencode_round_trips_multibyte_utf8() {
+		let s = "認可が拒否されました (access_denied) ☕/?&=+";
+		let encoded = urlencode(s);
+		assert!(encoded.is_ascii());
+		assert!(encoded.starts_with("%E8%AA%8D"));
+		assert_eq!(urldecode(&encoded).unwrap(), s);
+	}
+
+	#[test]
+	fn loopback_port_accepts_only_loopback_uris_with_a_port() {
+		assert_eq!(loopback_port("http://localhost:8080"), Some(8080));
+		assert_eq!(loopback_port("http://127.0.0.1:8765/oauth2callback"), Some(8765));
+		assert_eq!(loopback_port("http://localhost"), None);
+		assert_eq!(loopback_port("https://localhost:8080"), None);
+		assert_eq!(loopback_port("http://example.com:8080"), None);
+	}
+
+	#[test]
+	fn form_urldecode_treats_plus_as_space() {
+		assert_eq!(form_urldecode("a+b%2Bc").unwrap(), "a b+c");
+		assert_eq!(urldecode("a+b").unwrap(), "a+b");
+	}
+
+	#[test]
+	fn percent_decode_rejects_truncated_escapes() {
+		assert!(urldecode("%").is_err());
+		assert!(urldecode("%A").is_err());
+		assert!(urldecode("abc%4").is_err());
+	}
+
+	#[test]
+	fn percent_decode_rejects_signs_and_non_hex_digits() {
+		assert!(urldecode("%+F").is_err());
+		assert!(urldecode("%-1").is_err());
+		assert!(urldecode("%G0").is_err());
+	}
+
+	#[test]
+	fn percent_decode_rejects_invalid_utf8() {
+		assert!(urldecode("%FF").is_err());
+		assert!(urldecode("%E3%81").is_err());
+	}
+
+	#[test]
+	fn query_parameters_keep_keys_without_values_and_repeated_keys() {
+		let query = QueryParameters::parse("flag&a=1&&a=2&empty=").unwrap();
+		assert!(query.contains_key("flag"));
+		assert_eq!(query.get("flag"), Some(""));
+		assert_eq!(query.get("a"), Some("1"));
+		assert_eq!(query.get_all("a"), vec!["1", "2"]);
+		assert_eq!(query.get("empty"), Some(""));
+		assert!(!query.contains_key(""));
+		assert_eq!(
+			serde_json::to_string(&query).unwrap(),
+			r#"{"flag":"","a":"1","a":"2","empty":""}"#
+		);
+	}
+
+	#[test]
+	fn split_querystring_ignores_fragments() {
+		let query = split_querystring("/?state=s1&code=4%2F0A#code=fragment").unwrap();
+		assert_eq!(query.get("state"), Some("s1"));
+		assert_eq!(query.get_all("code"), vec!["4/0A"]);
+
+		assert!(!split_querystring("/#?code=x").unwrap().contains_key("code"));
+		assert!(!split_querystring("/oauth2callback").unwrap().contains_key("code"));
+	}
+
+	#[test]
+	fn query_parameters_reject_malformed_escapes() {
+		assert!(QueryParameters::parse("code=4%2").is_err());
+		assert!(QueryParameters::parse("co%ZZde=x").is_err());
+		assert!(split_querystring("/?state=%E3%81").is_err());
+	}
+
+	#[test]
+	fn read_http_request_reads_a_form_post_body() {
+		let body = "state=s1&code=4%2F0A+b";
+		let text = format!(
+			"POST / HTTP/1.1\r\nHost: localhost\r\ncontent-type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+			body.len(),
+			body
+		);
+		let request = read_http_request(&mut text.as_bytes()).unwrap();
+		assert_eq!(request.method, "POST");
+		assert_eq!(request.target, "/");
+		assert_eq!(request.header("Host"), Some("localhost"));
+
+		let query = diagnose_http_request(&request).unwrap();
+		assert_eq!(query.get("state"), Some("s1"));
+		assert_eq!(query.get("code"), Some("4/0A b"));
+	}
+
+	#[test]
+	fn diagnose_http_request_rejects_other_post_content_types() {
+		let text =
+			"POST /?state=s1 HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+		let request = read_http_request(&mut text.as_bytes()).unwrap();
+		let error = diagnose_http_request(&request).unwrap_err().to_string();
+		assert!(error.contains("Content-Type"), "{}", error);
+	}
+
+	#[test]
+	fn read_http_request_bounds_headers_and_body() {
+		let text = format!("GET /?{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_REQUEST_HEADER_SIZE));
+		let error = read_http_request(&mut text.as_bytes()).unwrap_err().to_string();
+		assert!(error.contains("ヘッダーが大きすぎます"), "{}", error);
+
+		let headers = "X-Padding: 0123456789abcdef\r\n".repeat(MAX_REQUEST_HEADER_SIZE / 16);
+		let text = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+		let error = read_http_request(&mut text.as_bytes()).unwrap_err().to_string();
+		assert!(error.contains("ヘッダーが大きすぎます"), "{}", error);
+
+		let text = format!(
+			"POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+			MAX_REQUEST_BODY_SIZE + 1
+		);
+		let error = read_http_request(&mut text.as_bytes()).unwrap_err().to_string();
+		assert!(error.contains("本文が大きすぎます"), "{}", error);
+
+		assert!(read_http_request(&mut "\r\n".as_bytes()).is_err());
+	}
 }
@@ -0,0 +1,155 @@
+//!
+//! アクセストークンの検証方針
+//!
+//! tokeninfo の結果を、別のアプリケーション向けに発行されたトークンのすり替えなどに対して検証します。
+
+use crate::error::OAuth2Error;
+use crate::gauth2::TokenVerificationResult;
+
+///
+/// アクセストークンの検証方針
+///
+#[derive(Debug, Clone)]
+pub struct VerificationPolicy {
+	/// 想定する aud (None であれば client_id)
+	pub audience: Option<String>,
+	/// azp がある場合に、aud と同じく確認するかどうか
+	pub check_authorized_party: bool,
+	/// 必要なスコープ
+	pub required_scopes: Vec<String>,
+	/// 必要な残りの有効期間
+	pub min_remaining_lifetime: Option<chrono::Duration>,
+	/// メールアドレスが確認済みであることを求めるかどうか
+	pub require_verified_email: bool,
+}
+
+impl Default for VerificationPolicy {
+	fn default() -> Self {
+		return Self {
+			audience: None,
+			check_authorized_party: true,
+			required_scopes: vec![],
+			min_remaining_lifetime: None,
+			require_verified_email: false,
+		};
+	}
+}
+
+impl VerificationPolicy {
+	///
+	/// tokeninfo の結果を検証します。
+	///
+	/// # Arguments
+	/// * `result` - tokeninfo の結果
+	/// * `client_id` - 既定の aud
+	///
+	pub fn check(
+		&self,
+		result: &TokenVerificationResult,
+		client_id: &str,
+	) -> Result<(), Box<dyn std::error::Error>> {
+		let expected = self.audience.as_deref().unwrap_or(client_id);
+
+		let aud = result.aud().unwrap_or_default();
+		if aud != expected {
+			return Err(Box::new(OAuth2Error::AudienceMismatch {
+				expected: expected.to_string(),
+				actual: aud.to_string(),
+			}));
+		}
+		if let Some(azp) = result.azp().filter(|_| self.check_authorized_party) {
+			if azp != expected {
+				return Err(Box::new(OAuth2Error::AuthorizedPartyMismatch {
+					expected: expected.to_string(),
+					actual: azp.to_string(),
+				}));
+			}
+		}
+
+		let scopes: Vec<&str> = result.scopes().into_iter().map(normalize_scope).collect();
+		let missing: Vec<String> = self
+			.required_scopes
+			.iter()
+			.filter(|s| !scopes.contains(&normalize_scope(s)))
+			.cloned()
+			.collect();
+		if !missing.is_empty() {
+			return Err(Box::new(OAuth2Error::MissingScopes(missing)));
+		}
+
+		if let Some(required) = self.min_remaining_lifetime {
+			let remaining = result.expires_in().unwrap_or_default();
+			if remaining < required.num_seconds() {
+				return Err(Box::new(OAuth2Error::InsufficientLifetime {
+					remaining_seconds: remaining,
+					required_seconds: required.num_seconds(),
+				}));
+			}
+		}
+
+		if self.require_verified_email && result.email_verified() != Some(true) {
+			return Err(Box::new(OAuth2Error::EmailNotVerified(
+				result.email().map(|e| e.to_string()),
+			)));
+		}
+		return Ok(());
+	}
+}
+
+///
+/// スコープの短縮名を、tokeninfo が返す URL の形式に変換します。
+///
+/// tokeninfo の scope には、認可リクエストで email, profile と指定したスコープも URL の形式で含まれます。
+///
+fn normalize_scope(scope: &str) -> &str {
+	return match scope {
+		"email" => "https://www.googleapis.com/auth/userinfo.email",
+		"profile" => "https://www.googleapis.com/auth/userinfo.profile",
+		_ => scope,
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// tokeninfo の結果を返します。
+	fn tokeninfo(scope: &str) -> TokenVerificationResult {
+		return serde_json::from_value(serde_json::json!({
+			"aud": "client-id",
+			"azp": "client-id",
+			"scope": scope,
+			"expires_in": "3599",
+		}))
+		.unwrap();
+	}
+
+	#[test]
+	fn short_scope_names_match_the_urls_returned_by_tokeninfo() {
+		let policy = VerificationPolicy {
+			required_scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+			..Default::default()
+		};
+		let result = tokeninfo(
+			"https://www.googleapis.com/auth/userinfo.email https://www.googleapis.com/auth/userinfo.profile openid",
+		);
+
+		policy.check(&result, "client-id").unwrap();
+	}
+
+	#[test]
+	fn reports_the_missing_scopes_as_specified() {
+		let policy = VerificationPolicy {
+			required_scopes: vec![
+				"email".to_string(),
+				"https://www.googleapis.com/auth/drive".to_string(),
+			],
+			..Default::default()
+		};
+		let result = tokeninfo("https://www.googleapis.com/auth/userinfo.email openid");
+
+		let e = policy.check(&result, "client-id").unwrap_err();
+		assert!(e.to_string().contains("https://www.googleapis.com/auth/drive"));
+		assert!(!e.to_string().contains("email"));
+	}
+}